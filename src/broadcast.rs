@@ -0,0 +1,82 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use crate::packet::{Message, Packet, SharedPacket, ID};
+use crate::socket::Socket;
+
+/// Connected sockets, by id
+#[doc(hidden)]
+pub type Clients = Arc<RwLock<HashMap<Arc<String>, Socket>>>;
+
+/// Ids of the sockets in each room
+#[doc(hidden)]
+pub type Rooms = Arc<RwLock<HashMap<String, HashSet<Arc<String>>>>>;
+
+/// Recipients of a message sent to several clients, returned by `Server::to`
+/// and `Socket::broadcast`
+#[derive(Clone)]
+pub struct Broadcast {
+    clients: Clients,
+    rooms: Rooms,
+    to: Vec<String>,
+    except: HashSet<String>,
+}
+
+impl Broadcast {
+    #[doc(hidden)]
+    pub fn new(clients: Clients, rooms: Rooms) -> Broadcast {
+        Broadcast {
+            clients,
+            rooms,
+            to: vec![],
+            except: HashSet::new(),
+        }
+    }
+
+    /// Also send to the sockets in `room`. Until a room is added, the message
+    /// is sent to every client.
+    pub fn to(mut self, room: &str) -> Broadcast {
+        self.to.push(room.to_string());
+        self
+    }
+
+    /// Don't send to the socket with id `sid`
+    pub fn except(mut self, sid: &str) -> Broadcast {
+        self.except.insert(sid.to_string());
+        self
+    }
+
+    /// Sockets the message is sent to. A socket in several of the rooms is
+    /// only included once.
+    pub fn sockets(&self) -> Vec<Socket> {
+        let clients = self.clients.read().unwrap();
+        if self.to.is_empty() {
+            return clients.iter()
+                .filter(|&(sid, _)| !self.except.contains(sid.as_str()))
+                .map(|(_, so)| so.clone())
+                .collect();
+        }
+
+        let rooms = self.rooms.read().unwrap();
+        let mut sids = HashSet::new();
+        for room in &self.to {
+            if let Some(members) = rooms.get(room) {
+                sids.extend(members.iter().filter(|sid| !self.except.contains(sid.as_str())));
+            }
+        }
+        sids.into_iter().filter_map(|sid| clients.get(sid).cloned()).collect()
+    }
+
+    /// Send a message to each recipient. The packet is encoded at most once
+    /// per format, and the encoding is shared across recipients.
+    pub fn send<M: Into<Message>>(&self, msg: M) {
+        let packet = SharedPacket::new(Packet {
+            id: ID::Message,
+            data: msg.into(),
+        });
+        // collected first, so that no lock is held while sending
+        for so in self.sockets() {
+            so.emit_shared(packet.clone());
+        }
+    }
+}
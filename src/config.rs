@@ -1,36 +1,176 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::string::String;
-use std::str::FromStr;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use iron::request::Request;
+use std::any::Any;
+use hyper::{Request, StatusCode};
+pub use cookie::SameSite;
 use crypto::sha2::Sha256;
 use crypto::digest::Digest;
 use rand::Rng;
 use rand::os::OsRng;
 
+/// Function generating the id of a new socket from its handshake request
+pub type IdGenerator = dyn Fn(&Request<()>) -> String + Send + Sync;
+
+/// Function deciding whether a handshake request is allowed
+pub type AllowRequest = dyn Fn(&Request<()>) -> Allow + Send + Sync;
+
+/// Outcome of `Config::allow_request`
+pub enum Allow {
+    /// Open a new socket for the client
+    Accept,
+    /// Open a new socket for the client, attaching data to it that can be
+    /// read with `Socket::auth`
+    AcceptWith(Box<dyn Any + Send + Sync>),
+    /// Refuse the handshake with the given HTTP status and error message
+    Reject(StatusCode, String),
+}
+
+/// Source of the current time, used to schedule pings and detect timed out
+/// clients.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Default value of `clock`, reading the system's monotonic clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Set of origins allowed to connect or make cross-origin requests
+#[derive(Clone)]
+pub enum Origins {
+    /// Any origin
+    Any,
+    /// Origins in the list, such as `https://example.com`. Subdomains can be
+    /// matched with a wildcard, as in `https://*.example.com`.
+    List(Vec<String>),
+    /// Origins for which the function returns true
+    Predicate(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl Origins {
+    /// Whether `origin` is allowed
+    pub fn allows(&self, origin: &str) -> bool {
+        match *self {
+            Origins::Any => true,
+            Origins::List(ref list) => list.iter().any(|o| origin_matches(o, origin)),
+            Origins::Predicate(ref func) => func(origin),
+        }
+    }
+}
+
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    match pattern.find("*.") {
+        Some(i) => {
+            let (scheme, domain) = (&pattern[..i], &pattern[i + 1..]);
+            origin.len() > pattern.len() - 1 && origin.starts_with(scheme) &&
+            origin.ends_with(domain) &&
+            !origin[scheme.len()..origin.len() - domain.len()].contains(['/', ':'])
+        }
+        None => pattern == origin,
+    }
+}
+
+/// Cross-origin resource sharing settings, letting browsers make polling
+/// requests from pages served by another origin.
+#[derive(Clone)]
+pub struct Cors {
+    /// Origins allowed to make requests (`Origins::Any`)
+    pub origins: Origins,
+    /// Whether requests can include cookies and HTTP authentication (`false`)
+    pub credentials: bool,
+    /// Response headers that scripts are allowed to read (none)
+    pub exposed_headers: Vec<String>,
+    /// How long browsers can cache the result of a preflight request. If
+    /// `None`, the browser default is used. (`None`)
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors {
+            origins: Origins::Any,
+            credentials: false,
+            exposed_headers: vec![],
+            max_age: None,
+        }
+    }
+}
+
 pub struct Config {
-    /// Duration before a pong packet after which to consider the connection
-    /// closed (60 seconds)
+    /// Duration to wait for a pong packet, on top of `ping_interval`, after
+    /// which to consider the connection closed (60 seconds)
     pub ping_timeout: Duration,
     /// Duration to wait before sending a new ping packet (25 seconds)
     pub ping_interval: Duration,
+    /// Clock driving the heartbeat. Default value is `SystemClock`
+    pub clock: Arc<dyn Clock>,
+    /// Duration to wait for a WebSocket upgrade to complete before giving up
+    /// on it, leaving the client on its current transport (10 seconds)
+    pub upgrade_timeout: Duration,
+    /// Longest time a polling GET request is held open while there is nothing
+    /// to send to the client, after which it is answered with a noop packet
+    /// (30 seconds)
+    pub poll_duration: Duration,
+    /// Maximum size in bytes of a polling request body or WebSocket message.
+    /// Larger requests are refused with a 413 status, and larger messages
+    /// close the socket. (1 MB)
+    pub max_http_buffer_size: usize,
     /// Name of the HTTP cookie that contains the client sid to send as part
-    /// of handshake response headers. Set to `None` to send a cookie.
+    /// of handshake response headers. Set to `None` to not send a cookie.
+    /// (`Some("io")`)
     pub cookie: Option<String>,
     /// Path of the above cookie option. If `None`, no path will be sent, which
     /// means browsers will only send the cookie on the engine.io attached path
     /// (`Some("/engine.io")`). Set this to `Some("/")` to send the io cookie
     /// on all requests. (`None`)
     pub cookie_path: Option<String>,
-    /// Generate a socket id. Takes an Iron `Request`, and returns the id String.
-    /// Default value is `generate_id`
-    pub generate_id: Arc<Box<Fn(&Request) -> String>>,
+    /// Domain of the above cookie option. If `None`, browsers will only send
+    /// the cookie to the host that set it. (`None`)
+    pub cookie_domain: Option<String>,
+    /// Whether the cookie is hidden from scripts running in the browser.
+    /// (`true`)
+    pub cookie_http_only: bool,
+    /// Whether the cookie is only sent over HTTPS. (`false`)
+    pub cookie_secure: bool,
+    /// `SameSite` attribute of the cookie. If `None`, no attribute will be
+    /// sent. (`Some(SameSite::Lax)`)
+    pub cookie_same_site: Option<SameSite>,
+    /// Generate a socket id. Takes the handshake request without its body,
+    /// and returns the id String. Default value is `generate_id`
+    pub generate_id: Arc<IdGenerator>,
+    /// Decide whether to accept a handshake before any state is allocated for
+    /// the client. Takes the handshake request without its body, whose
+    /// `SocketAddr` extension holds the client address. If `None`, all
+    /// handshakes are accepted. (`None`)
+    pub allow_request: Option<Arc<AllowRequest>>,
+    /// CORS settings. If `None`, no CORS headers are sent, and preflight
+    /// requests are refused. (`None`)
+    pub cors: Option<Cors>,
+    /// Whether the server is behind a proxy terminating TLS, whose
+    /// `X-Forwarded-Proto` header is trusted to tell if a request was made
+    /// over HTTPS. Otherwise only the request URI scheme is used, as any
+    /// client can send the header. (`false`)
+    pub trust_proxy: bool,
+    /// Origins allowed to connect, checked against the `Origin` header, or
+    /// the `Referer` header if there is none, of every request. Other
+    /// requests are refused with `Error::Forbidden`. Requests with neither
+    /// header do not come from a browser page and are allowed, except for
+    /// JSONP requests. If `None`, all origins are allowed. (`None`)
+    pub allowed_origins: Option<Origins>,
 }
 
 /// Default value of `generate_id`
-pub fn generate_id(r: &Request) -> String {
+pub fn generate_id(r: &Request<()>) -> String {
     let mut hasher = Sha256::new();
-    hasher.input_str(format!("{}{}", r.remote_addr, OsRng::new().unwrap().next_u32()).as_str());
+    let addr = r.extensions().get::<SocketAddr>();
+    hasher.input_str(format!("{:?}{}", addr, OsRng::new().unwrap().next_u32()).as_str());
     hasher.result_str()
 }
 
@@ -39,9 +179,41 @@ impl Default for Config {
         Config {
             ping_timeout: Duration::from_millis(60000),
             ping_interval: Duration::from_millis(25000),
-            cookie: Some(String::from_str("io").unwrap()),
+            clock: Arc::new(SystemClock),
+            upgrade_timeout: Duration::from_millis(10000),
+            poll_duration: Duration::from_millis(30000),
+            max_http_buffer_size: 1_000_000,
+            cookie: Some(String::from("io")),
             cookie_path: None,
-            generate_id: Arc::new(Box::new(generate_id)),
+            cookie_domain: None,
+            cookie_http_only: true,
+            cookie_secure: false,
+            cookie_same_site: Some(SameSite::Lax),
+            generate_id: Arc::new(generate_id),
+            allow_request: None,
+            cors: None,
+            trust_proxy: false,
+            allowed_origins: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Origins;
+
+    #[test]
+    fn origins() {
+        let origins = Origins::List(vec!["https://example.com".to_string(),
+                                         "https://*.example.org".to_string()]);
+        assert!(origins.allows("https://example.com"));
+        assert!(!origins.allows("http://example.com"));
+        assert!(!origins.allows("https://www.example.com"));
+        assert!(origins.allows("https://www.example.org"));
+        assert!(origins.allows("https://a.b.example.org"));
+        assert!(!origins.allows("https://example.org"));
+        assert!(!origins.allows("https://.example.org"));
+        assert!(!origins.allows("https://evil.com/.example.org"));
+        assert!(!origins.allows("https://evilexample.org"));
+    }
+}
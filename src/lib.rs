@@ -1,41 +1,29 @@
 //! ## Hello World
 //!
 //! ```no_run
-//! extern crate engine_io;
-//! extern crate iron;
-//!
-//! use iron::prelude::*;
+//! use engine_io::packet::Message;
 //! use engine_io::server::Server;
 //!
-//! fn main() {
+//! #[tokio::main]
+//! async fn main() {
 //!     let s = Server::new();
 //!     s.on_connection(|so| {
 //!         println!("connected to {}", so.id());
-//!         so.on_message(|m| {
-//!             println!("message: {}", String::from_utf8(m.to_vec()).unwrap());
+//!         so.on_message(|m| match *m {
+//!             Message::Text(ref text) => println!("message: {}", text),
+//!             Message::Binary(ref data) => println!("binary message: {:?}", data),
 //!         });
-//!         so.send(("Hello, world!").as_bytes().to_vec())
+//!         so.send("Hello, world!")
 //!     });
 //!
 //!     println!("listening");
-//!     Iron::new(s).http("localhost:3000").unwrap();
+//!     s.listen("localhost:3000").await.unwrap();
 //! }
 //! ```
 
-#[macro_use]
-extern crate iron;
-extern crate rand;
-extern crate url;
-extern crate rustc_serialize as serialize;
-extern crate crypto;
-extern crate modifier;
-extern crate cookie;
-extern crate time;
-extern crate hyper;
-#[macro_use]
-extern crate log;
-
 pub mod packet;
 pub mod server;
 pub mod socket;
 pub mod config;
+pub mod listener;
+pub mod broadcast;
@@ -0,0 +1,86 @@
+use std::sync::{Arc, RwLock, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+type List<F> = RwLock<Vec<(usize, Arc<F>)>>;
+
+/// Listeners registered for an event, called in the order they were added.
+#[doc(hidden)]
+pub struct Listeners<F: ?Sized>(Arc<List<F>>);
+
+impl<F: ?Sized + Send + Sync + 'static> Listeners<F> {
+    pub fn new() -> Listeners<F> {
+        Listeners(Arc::new(RwLock::new(Vec::new())))
+    }
+
+    pub fn add(&self, f: Arc<F>) -> Handle {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        self.0.write().unwrap().push((id, f));
+
+        let list: Weak<List<F>> = Arc::downgrade(&self.0);
+        Handle {
+            remove: Box::new(move || {
+                if let Some(list) = list.upgrade() {
+                    list.write().unwrap().retain(|&(i, _)| i != id);
+                }
+            }),
+        }
+    }
+
+    /// The current listeners. They are called without the list being locked,
+    /// so that listeners can add or remove listeners.
+    pub fn get(&self) -> Vec<Arc<F>> {
+        self.0.read().unwrap().iter().map(|(_, f)| f.clone()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+}
+
+impl<F: ?Sized + Send + Sync + 'static> Default for Listeners<F> {
+    fn default() -> Listeners<F> {
+        Listeners::new()
+    }
+}
+
+impl<F: ?Sized> Clone for Listeners<F> {
+    fn clone(&self) -> Listeners<F> {
+        Listeners(self.0.clone())
+    }
+}
+
+/// Handle to a listener added by one of the `on_*` methods, which can be used
+/// to remove it. Dropping the handle keeps the listener.
+pub struct Handle {
+    remove: Box<dyn Fn() + Send + Sync>,
+}
+
+impl Handle {
+    /// Remove the listener, so that it is no longer called
+    pub fn remove(self) {
+        (self.remove)()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::Listeners;
+
+    #[test]
+    fn add_remove() {
+        let listeners: Listeners<dyn Fn() -> u32 + Send + Sync> = Listeners::new();
+        let first = listeners.add(Arc::new(|| 1));
+        let _second = listeners.add(Arc::new(|| 2));
+        let called = |l: &Listeners<dyn Fn() -> u32 + Send + Sync>| -> Vec<u32> {
+            l.get().iter().map(|f| f()).collect()
+        };
+        assert_eq!(called(&listeners), vec![1, 2]);
+
+        first.remove();
+        assert_eq!(called(&listeners), vec![2]);
+        assert!(!listeners.is_empty());
+    }
+}
@@ -1,13 +1,14 @@
 use std::str;
-use std::vec::IntoIter;
+use std::borrow::Cow;
+use std::ops::Deref;
+use std::sync::{Arc, OnceLock};
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::string::{FromUtf8Error, ToString};
 use std::num::ParseIntError;
 
-use iron::response::Response;
-use serialize::base64::{FromBase64, ToBase64, Config, CharacterSet, Newline, FromBase64Error};
-use modifier::Modifier;
+use bytes::Bytes;
+use rustc_serialize::base64::{FromBase64, ToBase64, Config, CharacterSet, Newline, FromBase64Error};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ID {
@@ -20,10 +21,115 @@ pub enum ID {
     Noop = 6,
 }
 
+/// Version of the engine.io protocol spoken by a client, as sent in the `EIO`
+/// query parameter.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    V3 = 3,
+    V4 = 4,
+}
+
+impl Protocol {
+    /// Parse the value of the `EIO` query parameter. Clients that don't send
+    /// one are assumed to speak version 3.
+    pub fn from_query(eio: Option<&str>) -> Option<Protocol> {
+        match eio {
+            None | Some("3") => Some(Protocol::V3),
+            Some("4") => Some(Protocol::V4),
+            _ => None,
+        }
+    }
+}
+
+// Separates the packets of a version 4 payload.
+const RECORD_SEPARATOR: u8 = 0x1e;
+
+const BASE64_CONFIG: Config = Config {
+    char_set: CharacterSet::Standard,
+    newline: Newline::LF,
+    pad: true,
+    line_length: None,
+};
+
+/// Data carried by a packet, either UTF-8 text or binary data. Binary data
+/// is always sent as binary, even if it happens to be valid UTF-8.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Message {
+    Text(String),
+    Binary(Bytes),
+}
+
+impl Message {
+    pub fn as_bytes(&self) -> &[u8] {
+        match *self {
+            Message::Text(ref text) => text.as_bytes(),
+            Message::Binary(ref data) => data,
+        }
+    }
+
+    /// The text of the message, if it is not binary
+    pub fn as_text(&self) -> Option<&str> {
+        match *self {
+            Message::Text(ref text) => Some(text),
+            Message::Binary(_) => None,
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        matches!(*self, Message::Binary(_))
+    }
+
+    /// Length of the message in bytes
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_bytes().is_empty()
+    }
+}
+
+/// An empty text message
+impl Default for Message {
+    fn default() -> Message {
+        Message::Text(String::new())
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message::Text(text)
+    }
+}
+
+impl<'a> From<&'a str> for Message {
+    fn from(text: &'a str) -> Message {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Bytes> for Message {
+    fn from(data: Bytes) -> Message {
+        Message::Binary(data)
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Message {
+        Message::Binary(data.into())
+    }
+}
+
+impl<'a> From<&'a [u8]> for Message {
+    fn from(data: &'a [u8]) -> Message {
+        Message::Binary(Bytes::copy_from_slice(data))
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct Packet {
     pub id: ID,
-    pub data: Vec<u8>,
+    pub data: Message,
 }
 
 #[derive(Debug)]
@@ -31,6 +137,7 @@ pub enum Error {
     InvalidPacketID(u8),
     InvalidLengthDigit(u32),
     InvalidLengthCharacter(u8),
+    InvalidPayloadType(u8),
     IncompletePacket,
     EmptyPacket,
     FromBase64Error(FromBase64Error),
@@ -41,14 +148,16 @@ pub enum Error {
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self {
-            &Error::InvalidPacketID(id) => write!(f, "Invalid Packet ID: {}", id),
-            &Error::InvalidLengthDigit(d) => write!(f, "Invalid length digit: {}", d),
-            &Error::InvalidLengthCharacter(d) => write!(f, "Invalid length character: {}", d),
-            &Error::IncompletePacket => write!(f, "Incomplete Packet"),
-            &Error::EmptyPacket => write!(f, "Empty Packet"),
-            &Error::FromBase64Error(e) => write!(f, "FromBase64Error: {}", e),
-            &Error::Utf8Error(e) => write!(f, "Utf8Error: {}", e),
+        match *self {
+            Error::InvalidPacketID(id) => write!(f, "Invalid Packet ID: {}", id),
+            Error::InvalidLengthDigit(d) => write!(f, "Invalid length digit: {}", d),
+            Error::InvalidLengthCharacter(d) => write!(f, "Invalid length character: {}", d),
+            Error::InvalidPayloadType(t) => write!(f, "Invalid payload type: {}", t),
+            Error::IncompletePacket => write!(f, "Incomplete Packet"),
+            Error::EmptyPacket => write!(f, "Empty Packet"),
+            Error::FromBase64Error(e) => write!(f, "FromBase64Error: {}", e),
+            Error::Utf8Error(e) => write!(f, "Utf8Error: {}", e),
+            Error::FromUtf8Error(ref e) => write!(f, "FromUtf8Error: {}", e),
              _ => {write!(f, "oops")},
         }
     }
@@ -88,149 +197,368 @@ fn u8_to_id(u: u8) -> Result<ID, Error> {
 
 impl Packet {
     pub fn from_bytes(bytes: &[u8]) -> Result<Packet, Error> {
-        if bytes.len() == 0 {
+        if bytes.is_empty() {
             return Err(Error::EmptyPacket)
         }
 
         let mut base64 = false;
         let id = if bytes[0] == b'b' {
             base64 = true;
-            if bytes.len() < 1 {
+            if bytes.len() < 2 {
                 return Err(Error::IncompletePacket)
             }
-            try!(u8_to_id(bytes[1]))
+            u8_to_id(bytes[1])?
         } else {
-            try!(u8_to_id(bytes[0]))
+            u8_to_id(bytes[0])?
         };
-        let mut data = Vec::new();
-
-        data.extend(bytes.iter().skip(1));
         Ok(Packet{
-            id: id,
+            id,
             data: if base64 {
-                try!(data.from_base64())
+                Message::Binary(bytes[2..].from_base64()?.into())
             } else {
-                data
+                Message::Text(String::from_utf8(bytes[1..].to_vec())?)
             },
         })
     }
 
-    fn is_binary(&self) -> bool {
-        if let Err(_) = str::from_utf8(self.data.as_slice()) {
-            true
-        } else {
-            false
+    /// Decode a version 4 packet. Binary messages are base64 encoded, and
+    /// prefixed with `b` in place of the packet ID.
+    pub fn from_bytes_v4(bytes: &[u8]) -> Result<Packet, Error> {
+        if bytes.first() == Some(&b'b') {
+            return Ok(Packet {
+                id: ID::Message,
+                data: Message::Binary(bytes[1..].from_base64()?.into()),
+            });
         }
+
+        Packet::from_bytes(bytes)
+    }
+
+    /// Decode a packet sent as a binary WebSocket frame, where the first byte
+    /// is the packet ID as a number rather than an ASCII digit.
+    pub fn from_binary(bytes: &[u8]) -> Result<Packet, Error> {
+        if bytes.is_empty() {
+            return Err(Error::EmptyPacket)
+        }
+        if bytes[0] > ID::Noop as u8 {
+            return Err(Error::InvalidPacketID(bytes[0]))
+        }
+
+        Ok(Packet{
+            id: u8_to_id(bytes[0] + b'0')?,
+            data: Message::Binary(Bytes::copy_from_slice(&bytes[1..])),
+        })
+    }
+
+    #[doc(hidden)]
+    pub fn is_binary(&self) -> bool {
+        self.data.is_binary()
     }
 
     pub fn encode(&self) -> Vec<u8> {
         let mut vec = Vec::new();
 
         vec.extend_from_slice((self.id as u8).to_string().as_bytes());
-        vec.extend_from_slice(self.data.as_slice());
+        vec.extend_from_slice(self.data.as_bytes());
 
         vec
     }
 
-    pub fn encode_to(&self, v: &mut Vec<u8>) {
-        v.extend_from_slice((self.id as u8).to_string().as_bytes());
-        match String::from_utf8(self.data.clone()) {
-            Ok(s) => {
-                for c in s.chars() {
-                    if c == '"' {
-                        v.push('\\' as u8);
-                        v.push('"' as u8);
-                    } else {
-                        v.push(c as u8);
-                    }
-                }
-            }
-            Err(_) => v.extend_from_slice(self.data.as_slice()),
-        };
+    /// Encode the packet for a binary WebSocket frame.
+    pub fn encode_binary(&self) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(self.data.len() + 1);
+
+        vec.push(self.id as u8);
+        vec.extend_from_slice(self.data.as_bytes());
+
+        vec
+    }
+}
+
+/// Encodings of a packet, used to build payloads and WebSocket frames
+#[doc(hidden)]
+pub trait Encode {
+    fn packet(&self) -> &Packet;
+    /// The packet ID as an ASCII digit, followed by the data
+    fn encoded(&self) -> Cow<'_, [u8]>;
+    /// The packet ID as a byte, followed by the data
+    fn encoded_binary(&self) -> Cow<'_, [u8]>;
+    /// The data, base64 encoded
+    fn base64(&self) -> Cow<'_, [u8]>;
+}
+
+impl Encode for Packet {
+    fn packet(&self) -> &Packet {
+        self
+    }
+
+    fn encoded(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.encode())
+    }
+
+    fn encoded_binary(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.encode_binary())
+    }
+
+    fn base64(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.data.as_bytes().to_base64(BASE64_CONFIG).into_bytes())
+    }
+}
+
+/// A packet whose encodings are computed the first time they are needed, and
+/// shared by its clones, so that a message broadcast to many clients is only
+/// encoded once per format.
+#[derive(Clone, Debug)]
+pub struct SharedPacket(Arc<Encodings>);
+
+#[derive(Debug)]
+struct Encodings {
+    packet: Packet,
+    encoded: OnceLock<Vec<u8>>,
+    binary: OnceLock<Vec<u8>>,
+    base64: OnceLock<Vec<u8>>,
+}
+
+impl SharedPacket {
+    pub fn new(packet: Packet) -> SharedPacket {
+        SharedPacket(Arc::new(Encodings {
+            packet,
+            encoded: OnceLock::new(),
+            binary: OnceLock::new(),
+            base64: OnceLock::new(),
+        }))
+    }
+}
+
+impl From<Packet> for SharedPacket {
+    fn from(packet: Packet) -> SharedPacket {
+        SharedPacket::new(packet)
+    }
+}
+
+impl Deref for SharedPacket {
+    type Target = Packet;
+
+    fn deref(&self) -> &Packet {
+        &self.0.packet
+    }
+}
+
+impl Encode for SharedPacket {
+    fn packet(&self) -> &Packet {
+        &self.0.packet
+    }
+
+    fn encoded(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.encoded.get_or_init(|| self.0.packet.encode()))
+    }
+
+    fn encoded_binary(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.binary.get_or_init(|| self.0.packet.encode_binary()))
+    }
+
+    fn base64(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.base64.get_or_init(|| self.0.packet.base64().into_owned()))
     }
 }
 
 #[derive(Clone)]
 pub struct Payload(pub Vec<u8>);
 
-impl Modifier<Response> for Payload {
-    fn modify(self, r: &mut Response) {
-        r.body = Some(Box::new(self.0));
+impl Payload {
+    /// Whether the payload uses the XHR2 binary format.
+    pub fn is_binary(&self) -> bool {
+        match self.0.first() {
+            Some(&b) => b <= 1,
+            None => false,
+        }
     }
 }
 
-pub fn encode_payload(packets: &Vec<Packet>,
-                      jsonp_index: Option<i32>,
-                      b64: bool,
-                      xhr2: bool)
-                      -> Payload {
+pub fn encode_payload<P: Encode>(packets: &[P],
+                                 jsonp_index: Option<i32>,
+                                 b64: bool,
+                                 xhr2: bool)
+                                 -> Payload {
+    if xhr2 && !b64 && jsonp_index.is_none() && packets.iter().any(|p| p.packet().is_binary()) {
+        return encode_binary_payload(packets);
+    }
+
     let mut data = Vec::new();
-    let mut jsonp = false;
 
-    if let Some(index) = jsonp_index {
-        data.extend_from_slice(format!("___eio[{}](\"", index).as_bytes());
-        jsonp = true;
+    for encode in packets {
+        let packet = encode.packet();
+        if packet.is_binary() {
+            let base64_data = encode.base64();
+            data.extend_from_slice((base64_data.len() + 2).to_string().as_bytes());
+            data.push(b':');
+            data.push(b'b');
+            data.extend_from_slice((packet.id as u8).to_string().as_bytes());
+            data.extend_from_slice(&base64_data);
+        } else {
+            // lengths are counted in UTF-16 code units, as JavaScript does
+            let len = packet.data.as_text().unwrap_or_default().encode_utf16().count();
+            data.extend_from_slice((len + 1).to_string().as_bytes());
+            data.push(b':');
+            data.extend_from_slice(&encode.encoded());
+        }
     }
 
-    for packet in packets {
-        let is_binary = packet.is_binary();
-
-        if (b64 || !xhr2) && is_binary {
-            let base64_data = packet.data.to_base64(Config {
-                char_set: CharacterSet::Standard,
-                newline: Newline::LF,
-                pad: true,
-                line_length: None,
-            });
-            for c in (base64_data.len() + 1).to_string().chars() {
-                data.push(c.to_digit(10).unwrap() as u8);
-            }
-            data.push(':' as u8);
-            data.push('b' as u8);
-            data.extend_from_slice((packet.id as u8).to_string().as_bytes());
-            data.extend_from_slice(base64_data.as_bytes());
+    match jsonp_index {
+        Some(index) => jsonp_payload(index, data),
+        None => Payload(data),
+    }
+}
+
+/// Encode a version 4 payload, where packets are separated by a record
+/// separator character rather than prefixed with their length.
+pub fn encode_payload_v4<P: Encode>(packets: &[P], jsonp_index: Option<i32>) -> Payload {
+    let mut data = Vec::new();
+
+    for (i, encode) in packets.iter().enumerate() {
+        if i != 0 {
+            data.push(RECORD_SEPARATOR);
+        }
+
+        if encode.packet().is_binary() {
+            data.push(b'b');
+            data.extend_from_slice(&encode.base64());
         } else {
-            if xhr2 {
-                data.push(is_binary as u8);
-                data.push(0);
-                data.push(255);
-            }
+            data.extend_from_slice(&encode.encoded());
+        }
+    }
+
+    match jsonp_index {
+        Some(index) => jsonp_payload(index, data),
+        None => Payload(data),
+    }
+}
 
-            data.extend_from_slice((packet.data.len() + 1).to_string().as_bytes());
+// Wraps a string payload in a call to the JSONP callback at `index`, passing
+// it as a JavaScript string literal.
+fn jsonp_payload(index: i32, data: Vec<u8>) -> Payload {
+    // binary packets are base64 encoded, so the payload is valid UTF-8
+    let data = String::from_utf8_lossy(&data);
+    Payload(format!("___eio[{}]({});", index, json_string(&data)).into_bytes())
+}
 
-            data.push(':' as u8);
-            packet.encode_to(&mut data);
+/// Quote `s` as a JSON string, which can also be embedded in JavaScript.
+#[doc(hidden)]
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            // line terminators in JavaScript, but not in JSON
+            '\u{2028}' | '\u{2029}' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
         }
     }
+    quoted.push('"');
+    quoted
+}
 
-    if jsonp {
-        data.extend_from_slice("\");".as_bytes());
+/// Decode a version 4 payload.
+pub fn decode_payload_v4(data: Vec<u8>) -> Result<Vec<Packet>, Error> {
+    if data.is_empty() {
+        return Err(Error::EmptyPacket);
+    }
+
+    data.split(|b| *b == RECORD_SEPARATOR).map(Packet::from_bytes_v4).collect()
+}
+
+// Each packet is encoded as a 0 (string) or 1 (binary) type byte, the length
+// of the packet as one byte per decimal digit, a 255 separator, and the packet.
+fn encode_binary_payload<P: Encode>(packets: &[P]) -> Payload {
+    let mut data = Vec::new();
+
+    for packet in packets {
+        let is_binary = packet.packet().is_binary();
+        let encoded = if is_binary {
+            packet.encoded_binary()
+        } else {
+            packet.encoded()
+        };
+
+        data.push(is_binary as u8);
+        for c in encoded.len().to_string().chars() {
+            data.push(c.to_digit(10).unwrap() as u8);
+        }
+        data.push(255);
+        data.extend_from_slice(&encoded);
     }
 
     Payload(data)
 }
 
-pub fn decode_payload(data: Vec<u8>, b64: bool, xhr2: bool) -> Result<Vec<Packet>, Error> {
-    if data.len() == 0 {
+pub fn decode_payload(data: Vec<u8>, _b64: bool, xhr2: bool) -> Result<Vec<Packet>, Error> {
+    if data.is_empty() {
         return Err(Error::EmptyPacket);
     }
 
     let mut packets = Vec::new();
 
-    if xhr2 {
+    // a string payload always starts with an ASCII length digit, whereas a
+    // binary one starts with a 0 or 1 type byte.
+    if xhr2 && data[0] <= 1 {
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            let is_binary = rest[0] == 1;
+            if rest[0] > 1 {
+                return Err(Error::InvalidPayloadType(rest[0]));
+            }
 
+            let mut len: usize = 0;
+            let mut i = 1;
+            loop {
+                match rest.get(i) {
+                    Some(&255) => break,
+                    Some(&d) if d <= 9 => {
+                        len = len.checked_mul(10)
+                            .and_then(|l| l.checked_add(d as usize))
+                            .ok_or(Error::InvalidLengthDigit(d as u32))?;
+                    }
+                    Some(&d) => return Err(Error::InvalidLengthDigit(d as u32)),
+                    None => return Err(Error::IncompletePacket),
+                }
+                i += 1;
+            }
+
+            rest = &rest[i + 1..];
+            if len > rest.len() {
+                return Err(Error::IncompletePacket);
+            }
+            packets.push(if is_binary {
+                Packet::from_binary(&rest[..len])?
+            } else {
+                Packet::from_bytes(&rest[..len])?
+            });
+            rest = &rest[len..];
+        }
     } else {
-        let mut data_iter: IntoIter<u8> = data.into_iter();
-        while data_iter.by_ref().len() != 0 {
-            let len = try!(usize::from_str_radix(&try!(
-                String::from_utf8(data_iter.by_ref().
-                                  take_while(|c| *c != b':').
-                                  collect::<Vec<u8>>())), 10));
-            if len > data_iter.by_ref().len() {
+        let data = String::from_utf8(data)?;
+        let mut rest = data.as_str();
+        while !rest.is_empty() {
+            let (len, packet) = rest.split_once(':').ok_or(Error::IncompletePacket)?;
+            let len = len.parse::<usize>()?;
+
+            // the length is in UTF-16 code units, as counted by JavaScript
+            let (mut units, mut end) = (0, 0);
+            for c in packet.chars() {
+                if units >= len {
+                    break;
+                }
+                units += c.len_utf16();
+                end += c.len_utf8();
+            }
+            if units != len {
                 return Err(Error::IncompletePacket);
             }
-            packets.push(try!(Packet::from_bytes(&data_iter.by_ref().take(len).
-                                                collect::<Vec<u8>>())))
+            packets.push(Packet::from_bytes(&packet.as_bytes()[..end])?);
+            rest = &packet[end..];
         }
     }
 
@@ -240,18 +568,139 @@ pub fn decode_payload(data: Vec<u8>, b64: bool, xhr2: bool) -> Result<Vec<Packet
 
 #[cfg(test)]
 mod tests {
-    use super::{decode_payload, ID};
+    use super::{decode_payload, encode_payload, decode_payload_v4, encode_payload_v4};
+    use super::{Message, Packet, ID};
     #[test]
     fn it_works() {
         let packets = decode_payload("6:4Hello11:4HelloWorld".to_string().into_bytes(), true, false).unwrap();
         assert_eq!(packets[0].id, ID::Message);
-        assert_eq!(packets[0].data, ("Hello".to_string().into_bytes()));
+        assert_eq!(packets[0].data, Message::from("Hello"));
         assert_eq!(packets[1].id, ID::Message);
-        assert_eq!(packets[1].data, ("HelloWorld".to_string().into_bytes()));
+        assert_eq!(packets[1].data, Message::from("HelloWorld"));
 
         let mut err = decode_payload("asd:asd".to_string().into_bytes(), true, false);
         assert!(err.is_err());
         err = decode_payload("10:2asd".to_string().into_bytes(), true, false);
         assert!(err.is_err());
     }
+
+    #[test]
+    fn binary_frame() {
+        let packet = Packet {
+            id: ID::Message,
+            data: Message::from(vec![0xff, 0x00, 0x01]),
+        };
+        let encoded = packet.encode_binary();
+        assert_eq!(encoded, vec![4, 0xff, 0x00, 0x01]);
+        assert_eq!(Packet::from_binary(&encoded).unwrap(), packet);
+
+        assert!(Packet::from_binary(&[7, 1]).is_err());
+        assert!(Packet::from_binary(&[]).is_err());
+    }
+
+    #[test]
+    fn binary_payload() {
+        let packets = vec![Packet {
+                               id: ID::Message,
+                               data: Message::from("Hello"),
+                           },
+                           Packet {
+                               id: ID::Message,
+                               data: Message::from(vec![0xff, 0x00, 0x01]),
+                           }];
+        let payload = encode_payload(&packets, None, false, true);
+        assert!(payload.is_binary());
+        assert_eq!(payload.0,
+                   vec![0, 6, 255, b'4', b'H', b'e', b'l', b'l', b'o', 1, 4, 255, 4, 0xff, 0x00,
+                        0x01]);
+        assert_eq!(decode_payload(payload.0, false, true).unwrap(), packets);
+
+        assert!(decode_payload(vec![0, 6, 255, b'4'], false, true).is_err());
+        assert!(decode_payload(vec![0, 10, 255, b'4'], false, true).is_err());
+        assert!(decode_payload(vec![1, 1], false, true).is_err());
+    }
+
+    #[test]
+    fn payload_v4() {
+        let packets = vec![Packet {
+                               id: ID::Message,
+                               data: Message::from("Hello"),
+                           },
+                           Packet {
+                               id: ID::Message,
+                               data: Message::from(vec![0xff, 0xfe]),
+                           },
+                           Packet {
+                               id: ID::Ping,
+                               data: Message::default(),
+                           }];
+        let payload = encode_payload_v4(&packets, None);
+        assert_eq!(payload.0, b"4Hello\x1eb//4=\x1e2".to_vec());
+        assert_eq!(decode_payload_v4(payload.0).unwrap(), packets);
+
+        let payload = encode_payload_v4(&[packets[0].clone()], Some(1));
+        assert_eq!(payload.0, b"___eio[1](\"4Hello\");".to_vec());
+
+        assert!(decode_payload_v4(b"4Hello\x1e".to_vec()).is_err());
+        assert!(decode_payload_v4(b"b!!".to_vec()).is_err());
+    }
+
+    #[test]
+    fn binary_utf8() {
+        // valid UTF-8 sent as binary must not turn into text
+        let packets = vec![Packet {
+                               id: ID::Message,
+                               data: Message::from(b"abc".to_vec()),
+                           },
+                           Packet {
+                               id: ID::Message,
+                               data: Message::from("abc"),
+                           }];
+        let payload = encode_payload(&packets, None, true, false);
+        assert_eq!(payload.0, b"6:b4YWJj4:4abc".to_vec());
+        assert_eq!(decode_payload(payload.0, true, false).unwrap(), packets);
+
+        let payload = encode_payload(&packets, None, false, true);
+        assert!(payload.is_binary());
+        assert_eq!(decode_payload(payload.0, false, true).unwrap(), packets);
+
+        let payload = encode_payload_v4(&packets, None);
+        assert_eq!(payload.0, b"bYWJj\x1e4abc".to_vec());
+        assert_eq!(decode_payload_v4(payload.0).unwrap(), packets);
+
+        assert!(Packet::from_bytes(b"4\xff").is_err());
+    }
+
+    #[test]
+    fn jsonp_escaping() {
+        let packets = vec![Packet {
+                               id: ID::Message,
+                               data: Message::from("\");alert(1);//\\\n\u{2028}"),
+                           }];
+        let payload = encode_payload_v4(&packets, Some(0));
+        assert_eq!(String::from_utf8(payload.0).unwrap(),
+                   r#"___eio[0]("4\");alert(1);//\\\u000a\u2028");"#);
+
+        let payload = encode_payload(&packets, Some(0), true, false);
+        assert_eq!(String::from_utf8(payload.0).unwrap(),
+                   r#"___eio[0]("18:4\");alert(1);//\\\u000a\u2028");"#);
+    }
+
+    #[test]
+    fn payload_utf16_length() {
+        let packets = vec![Packet {
+                               id: ID::Message,
+                               data: Message::from("é😀"),
+                           },
+                           Packet {
+                               id: ID::Message,
+                               data: Message::from("a"),
+                           }];
+        let payload = encode_payload(&packets, None, true, false);
+        assert_eq!(String::from_utf8(payload.0.clone()).unwrap(), "4:4é😀2:4a");
+        assert_eq!(decode_payload(payload.0, true, false).unwrap(), packets);
+
+        // a length ending within a surrogate pair
+        assert!(decode_payload("3:4é😀".to_string().into_bytes(), true, false).is_err());
+    }
 }
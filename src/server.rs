@@ -1,73 +1,153 @@
-use std::sync::{Arc, RwLock, Mutex};
+use std::sync::{Arc, RwLock};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+use std::net::SocketAddr;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::error::Error as StdError;
-use std::str::FromStr;
 use std::fmt;
-use std::thread::{sleep, spawn};
-use std::io::Read;
-
-use socket::{Socket, Transport};
-use packet::{Packet, ID, decode_payload, encode_payload};
-use config::Config;
-use modifier::Modifier;
-use iron::method::Method::{Get, Post};
-use iron::request::Request;
-use iron::response::Response;
-use iron::middleware::Handler;
-use iron::headers::{Header, Cookie, ConnectionOption, Connection, Date, HttpDate, ContentType};
-use hyper::mime::Mime;
-use cookie::Cookie as CookiePair;
-use url::form_urlencoded::parse;
-use iron::IronResult;
-use iron::status;
-use iron::error::IronError;
-use iron::headers::SetCookie;
-use url::{Url, form_urlencoded};
-use time;
+use std::io;
+use std::any::Any;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::broadcast::{Broadcast, Clients, Rooms};
+use crate::socket::{split_batch, Handshake, Outgoing, Socket, Transport};
+use crate::packet::{Message, Packet, Payload, Protocol, ID, encode_payload, encode_payload_v4};
+use crate::packet::Error as PacketError;
+use crate::packet::{json_string, Encode};
+use crate::config::{Allow, Config, Cors, Origins};
+use crate::listener::{Handle, Listeners};
+use bytes::Bytes;
+use cookie::Cookie;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use hyper::http::uri::Scheme;
+use hyper::body::{Body, Incoming};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::{watch, Mutex};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::time::{interval, sleep, timeout};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Message as WsMessage, Error as WsError};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+use tokio_tungstenite::tungstenite::protocol::frame::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tower_service::Service;
+
+// WebSocket connection to a client, generic over the underlying connection,
+// which is an upgraded hyper connection when serving requests.
+trait WebSocket: Stream<Item = Result<WsMessage, WsError>> + Sink<WsMessage, Error = WsError> +
+    Unpin + Send {}
+
+impl<W> WebSocket for W
+    where W: Stream<Item = Result<WsMessage, WsError>> + Sink<WsMessage, Error = WsError> +
+             Unpin + Send
+{
+}
+// How long `Server::listen` waits after failing to accept a connection
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+type Auth = Box<dyn Any + Send + Sync>;
+type ErrorCallback = dyn Fn(&Request<()>, Error) + Send + Sync;
 
 #[derive(Clone)]
 pub struct Server {
-    clients: Arc<RwLock<HashMap<Arc<String>, Socket>>>,
-    on_connection: Arc<RwLock<Option<Box<Fn(Socket) + 'static>>>>,
+    clients: Clients,
+    rooms: Rooms,
+    on_connection: Listeners<dyn Fn(Socket) + Send + Sync>,
+    on_connection_error: Listeners<ErrorCallback>,
+    accepted: Arc<Mutex<Option<UnboundedReceiver<Socket>>>>,
     ping_loop_started: Arc<AtomicBool>,
+    shutdown: Arc<watch::Sender<Shutdown>>,
     config: Arc<Config>,
 }
 
-#[derive(Debug, Copy, Clone)]
-enum Error {
+// Progress of `Server::shutdown`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Shutdown {
+    Running,
+    // handshakes are refused while clients are sent their last packets
+    Draining,
+    Stopped,
+}
+
+/// Errors returned to clients whose request is rejected, as defined by the
+/// engine.io protocol.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The transport is missing or not supported
     UnsupportedTransport,
+    /// The session ID is unknown, or not valid for the request
     InvalidSID,
+    /// A handshake was made with a method other than GET
+    BadHandshake,
+    /// The request is malformed
+    BadRequest,
+    /// The request was refused by the server
+    Forbidden,
+    /// The protocol version requested with `EIO` is not supported
+    UnsupportedProtocolVersion,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", format!("{}", self.description()))
+        f.write_str(match *self {
+            Error::UnsupportedTransport => "Transport unknown",
+            Error::InvalidSID => "Session ID unknown",
+            Error::BadHandshake => "Bad handshake method",
+            Error::BadRequest => "Bad request",
+            Error::Forbidden => "Forbidden",
+            Error::UnsupportedProtocolVersion => "Unsupported protocol version",
+        })
     }
 }
 
-impl StdError for Error {
-    fn description(&self) -> &'static str {
+impl StdError for Error {}
+
+impl Error {
+    /// engine.io error code, sent to the client along with the message
+    pub fn code(self) -> u8 {
         match self {
-            &Error::UnsupportedTransport => "unsupported/invalid transport",
-            &Error::InvalidSID => "invalid session ID",
+            Error::UnsupportedTransport => 0,
+            Error::InvalidSID => 1,
+            Error::BadHandshake => 2,
+            Error::BadRequest => 3,
+            Error::Forbidden => 4,
+            Error::UnsupportedProtocolVersion => 5,
         }
     }
-}
 
-impl Modifier<Response> for Error {
-    fn modify(self, res: &mut Response) {
-        res.body = Some(Box::new(String::from_str(self.description()).unwrap()))
+    /// HTTP status of the response
+    pub fn status(self) -> StatusCode {
+        match self {
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            _ => StatusCode::BAD_REQUEST,
+        }
     }
-}
 
-unsafe impl Send for Server {}
-unsafe impl Sync for Server {}
+    // engine.io clients expect errors as a JSON object with the error code
+    // and message
+    fn into_response(self) -> Response<Full<Bytes>> {
+        let body = format!(r#"{{"code":{},"message":"{}"}}"#, self.code(), self);
+        response(self.status(), "application/json", body)
+    }
+}
 
-macro_rules! make_err {
-    ($x:expr) => {Err(IronError::new(Box::new($x), $x));}
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
 }
 
 impl Server {
@@ -78,273 +158,1848 @@ impl Server {
     pub fn with_config(config: Config) -> Server {
         Server {
             clients: Arc::new(RwLock::new(HashMap::new())),
-            on_connection: Arc::new(RwLock::new(None)),
+            rooms: Arc::new(RwLock::new(HashMap::new())),
+            on_connection: Listeners::new(),
+            on_connection_error: Listeners::new(),
+            accepted: Arc::new(Mutex::new(None)),
             ping_loop_started: Arc::new(AtomicBool::new(false)),
+            shutdown: Arc::new(watch::Sender::new(Shutdown::Running)),
             config: Arc::new(config),
         }
     }
 
-    pub fn on_connection<F>(&self, f: F)
-        where F: Fn(Socket) + 'static
+    /// Add callback for when a client connects
+    pub fn on_connection<F>(&self, f: F) -> Handle
+        where F: Fn(Socket) + Send + Sync + 'static
     {
-        let mut data = self.on_connection.write().unwrap();
-        if let Some(ref b) = *data {
-            drop(b);
+        self.on_connection.add(Arc::new(f))
+    }
+
+    /// Wait for a client to connect, as an alternative to `on_connection`.
+    /// Clients are queued from the first call on, so clients connecting
+    /// before it are only passed to `on_connection` listeners. Messages sent
+    /// by a queued client are buffered until `Socket::messages` is called.
+    pub async fn accept(&self) -> Option<Socket> {
+        let mut accepted = self.accepted.lock().await;
+        if accepted.is_none() {
+            let (send, recv) = unbounded_channel();
+            self.on_connection(move |so| {
+                // before the client gets the handshake response, and can
+                // send messages
+                so.buffer_messages();
+                let _ = send.send(so);
+            });
+            *accepted = Some(recv);
         }
-        *data = Some(Box::new(f));
+
+        accepted.as_mut()?.recv().await
     }
 
-    pub fn close(&self) {
-        let data = self.clients.clone();
-        let mut map = data.write().unwrap();
+    /// Add callback for when a request is rejected with an error
+    pub fn on_connection_error<F>(&self, f: F) -> Handle
+        where F: Fn(&Request<()>, Error) + Send + Sync + 'static
+    {
+        self.on_connection_error.add(Arc::new(f))
+    }
+
+    // Calls `on_connection_error` before responding with `e`.
+    fn reject(&self, req: &Request<()>, e: Error) -> Response<Full<Bytes>> {
+        debug!("rejecting request to {}: {}", req.uri(), e);
+        for func in self.on_connection_error.get() {
+            func(req, e)
+        }
+        e.into_response()
+    }
+
+    // Runs `Config::allow_request` on a handshake, returning the data to
+    // attach to the new socket, or the response refusing the handshake.
+    #[allow(clippy::result_large_err)]
+    fn allow_request(&self, req: &Request<()>) -> Result<Option<Auth>, Response<Full<Bytes>>> {
+        let allow = match self.config.allow_request {
+            Some(ref func) => func(req),
+            None => return Ok(None),
+        };
 
-        for (_, socket) in map.iter_mut() {
+        match allow {
+            Allow::Accept => Ok(None),
+            Allow::AcceptWith(auth) => Ok(Some(auth)),
+            Allow::Reject(status, message) => {
+                let mut res = self.reject(req, Error::Forbidden);
+                *res.status_mut() = status;
+                *res.body_mut() = Full::new(Bytes::from(format!(r#"{{"code":{},"message":{}}}"#,
+                                                                Error::Forbidden.code(),
+                                                                json_string(&message))));
+                Err(res)
+            }
+        }
+    }
+
+    /// Send a message to every client
+    pub fn broadcast<M: Into<Message>>(&self, msg: M) {
+        Broadcast::new(self.clients.clone(), self.rooms.clone()).send(msg)
+    }
+
+    /// Recipients of a message to the clients in `room`. More rooms can be
+    /// added with `Broadcast::to`, and clients left out with
+    /// `Broadcast::except`.
+    pub fn to(&self, room: &str) -> Broadcast {
+        Broadcast::new(self.clients.clone(), self.rooms.clone()).to(room)
+    }
+
+    pub fn close(&self) {
+        // sockets remove themselves from the map when closed
+        for mut socket in self.sockets() {
             socket.close("closing server");
         }
+    }
+
+    /// Shut the server down gracefully. New handshakes are refused, and every
+    /// client is sent a close packet, which also ends its pending poll if
+    /// any. Sockets are closed once their packets are flushed, or once
+    /// `deadline` has passed, and are neither pinged nor timed out meanwhile.
+    /// `listen` then returns.
+    pub async fn shutdown(&self, deadline: Duration) {
+        let started = self.shutdown.send_if_modified(|stage| {
+            let running = *stage == Shutdown::Running;
+            if running {
+                *stage = Shutdown::Draining;
+            }
+            running
+        });
+        if !started {
+            // shut down by an earlier call, which may still be draining
+            return self.stopped().await;
+        }
 
-        map.clear();
+        let sockets = self.sockets();
+        for so in &sockets {
+            so.emit(Packet {
+                id: ID::Close,
+                data: Message::default(),
+            });
+        }
+        let drained = timeout(deadline, async {
+            for so in &sockets {
+                so.wait_buffered_below(1).await;
+            }
+        });
+        if drained.await.is_err() {
+            debug!("shutdown deadline passed before all packets were flushed");
+        }
+
+        // including sockets whose handshake was already under way
+        for mut so in self.sockets() {
+            so.close("server shutdown");
+        }
+        self.shutdown.send_replace(Shutdown::Stopped);
     }
 
-    pub fn get_socket(&self, c: Cookie) -> Option<Socket> {
-        for pair in c.0 {
-            if pair.name == "io" {
-                let map = self.clients.read().unwrap();
-                return match map.get(&pair.value) {
-                    Some(so) => Some(so.clone()),
-                    None => None,
-                };
+    // Resolves once `shutdown` has completed.
+    async fn stopped(&self) {
+        let mut stage = self.shutdown.subscribe();
+        // the sender lives as long as the server
+        let _ = stage.wait_for(|stage| *stage == Shutdown::Stopped).await;
+    }
+
+    /// The connected socket with id `sid`
+    pub fn socket(&self, sid: &str) -> Option<Socket> {
+        self.clients.read().unwrap().get(&sid.to_string()).cloned()
+    }
+
+    /// Snapshot of the connected sockets, which is not updated as clients
+    /// connect and disconnect
+    pub fn sockets(&self) -> Vec<Socket> {
+        self.clients.read().unwrap().values().cloned().collect()
+    }
+
+    /// Number of connected clients
+    pub fn clients_count(&self) -> usize {
+        self.clients.read().unwrap().len()
+    }
+
+    /// Close the socket with id `sid`, calling its `on_close` listeners with
+    /// `reason`. Returns false if there is no such socket.
+    pub fn close_socket(&self, sid: &str, reason: &str) -> bool {
+        match self.socket(sid) {
+            Some(mut so) => {
+                so.close(reason);
+                true
             }
+            None => false,
+        }
+    }
 
+    /// Look up the socket whose id is stored in the cookie named by
+    /// `Config::cookie`, given the value of a `Cookie` request header.
+    pub fn get_socket(&self, cookies: &str) -> Option<Socket> {
+        let name = self.config.cookie.as_ref()?;
+        for pair in Cookie::split_parse(cookies).flatten() {
+            if pair.name() == name {
+                let map = self.clients.read().unwrap();
+                return map.get(&pair.value().to_string()).cloned();
+            }
         }
         None
     }
 
+    /// Forget the socket with id `sid` without closing it, so that further
+    /// requests from its client are refused. Use `close_socket` to
+    /// disconnect a client.
     pub fn remove_socket(&self, sid: String) {
         let mut map = self.clients.write().unwrap();
         map.remove(&sid);
     }
 
-    fn open_connection(&self, req: &Request) -> IronResult<Response> {
-        debug!("opening new connection to {}", req.remote_addr);
-        let url: Url = req.url.clone().into_generic_url();
-        let pairs = url.query_pairs().into_owned();
-        let map = into_hashmap(pairs);
+    /// Serve engine.io on `addr`, handling each connection on its own task.
+    /// Returns once `shutdown` completes, or if `addr` can't be bound. Errors
+    /// accepting a connection, such as running out of file descriptors, are
+    /// logged and accepting resumes shortly after.
+    pub async fn listen<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.stopped() => return Ok(()),
+            };
+            let (stream, addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("failed to accept a connection: {}", e);
+                    tokio::select! {
+                        _ = sleep(ACCEPT_BACKOFF) => continue,
+                        _ = self.stopped() => return Ok(()),
+                    }
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move { server.serve_connection(stream, addr).await });
+        }
+    }
+
+    // Serves the requests read from the connection to the client at `addr`,
+    // and the WebSocket connections they are upgraded to.
+    async fn serve_connection<I>(&self, io: I, addr: SocketAddr)
+        where I: AsyncRead + AsyncWrite + Unpin + Send + 'static
+    {
+        let server = self.clone();
+        let service = service_fn(move |mut req: Request<Incoming>| {
+            let server = server.clone();
+            req.extensions_mut().insert(addr);
+            async move { Ok::<_, Infallible>(server.handle(req).await) }
+        });
+
+        let conn = http1::Builder::new()
+            .serve_connection(TokioIo::new(io), service)
+            .with_upgrades();
+        if let Err(e) = conn.await {
+            debug!("connection to {} failed: {}", addr, e);
+        }
+    }
+
+    /// Handle an engine.io request. The address of the client is read from
+    /// the `SocketAddr` request extension, as set by `listen`.
+    ///
+    /// Long-polling GET requests only complete once there are packets to send
+    /// to the client, without blocking the thread they run on.
+    pub async fn handle<B>(&self, req: Request<B>) -> Response<Full<Bytes>>
+        where B: Body,
+              B::Error: Into<Box<dyn StdError + Send + Sync>>
+    {
+        self.start_ping_loop();
+
+        let (parts, body) = req.into_parts();
+        let req = Request::from_parts(parts, ());
+        let origin = req.headers().get(header::ORIGIN).cloned();
+
+        let mut res = match self.config.cors {
+            Some(ref cors) if req.method() == Method::OPTIONS => preflight(&req, cors),
+            _ => self.handle_request(req, body).await,
+        };
+        if let (Some(cors), Some(origin)) = (self.config.cors.as_ref(), origin) {
+            set_cors_headers(cors, &origin, res.headers_mut());
+        }
+        res
+    }
+
+    async fn handle_request<B>(&self, req: Request<()>, body: B) -> Response<Full<Bytes>>
+        where B: Body,
+              B::Error: Into<Box<dyn StdError + Send + Sync>>
+    {
+        let map = query_map(&req);
+
+        if let Some(ref origins) = self.config.allowed_origins {
+            let allowed = match request_origin(&req) {
+                Some(origin) => origins.allows(&origin),
+                // JSONP requests are made by script tags, which can be told
+                // not to send a referer
+                None => !map.contains_key("j"),
+            };
+            if !allowed {
+                return self.reject(&req, Error::Forbidden);
+            }
+        }
+
+        match map.get("transport").map(|t| t.as_str()) {
+            Some("websocket") => return self.handle_websocket(req, &map),
+            Some("polling") => {}
+            // including requests of an existing session without a transport
+            _ => return self.reject(&req, Error::UnsupportedTransport),
+        }
+
+        // requests without a sid are handshakes, while the session cookie is
+        // only there for load balancers to stick clients to a server
+        let mut so = match map.get("sid") {
+            Some(sid) => {
+                match self.clients.read().unwrap().get(sid) {
+                    Some(so) => so.clone(),
+                    None => return self.reject(&req, Error::InvalidSID),
+                }
+            }
+            None if self.draining() => return status_response(StatusCode::SERVICE_UNAVAILABLE),
+            None => return self.open_connection(&req, &map),
+        };
+        // the session has been upgraded to another transport
+        if !so.is_polling() {
+            return self.reject(&req, Error::BadRequest);
+        }
+
+        match *req.method() {
+            Method::POST => {
+                let _posting = match so.start_post() {
+                    Some(guard) => guard,
+                    None => return self.reject_overlap(&req, &mut so),
+                };
+                let body = match Limited::new(body, self.config.max_http_buffer_size).collect().await {
+                    Ok(body) => body.to_bytes(),
+                    Err(e) if e.is::<LengthLimitError>() => {
+                        debug!("payload from {} is too large", so.id());
+                        so.close("payload too large");
+                        return status_response(StatusCode::PAYLOAD_TOO_LARGE);
+                    }
+                    Err(e) => {
+                        debug!("failed to read request from {}: {}", so.id(), e);
+                        return self.reject(&req, Error::BadRequest);
+                    }
+                };
+
+                // JSONP clients POST the payload as the `d` form field, while
+                // XHR clients send it as the request body.
+                let payload = if so.jsonp_index().is_some() {
+                    match form_urlencoded::parse(&body).find(|(q, _)| q == "d") {
+                        Some((_, val)) => val.replace("\\n", "\n").into_bytes(),
+                        None => return self.reject(&req, Error::BadRequest),
+                    }
+                } else {
+                    body.to_vec()
+                };
+
+                match so.decode_payload(payload) {
+                    Ok(packets) => {
+                        for packet in packets {
+                            self.handle_packet(&mut so, packet);
+                        }
+                    }
+                    Err(e) => {
+                        debug!("invalid payload from {}: {}", so.id(), e);
+                        return self.reject(&req, Error::BadRequest);
+                    }
+                }
+
+                response(StatusCode::OK, "text/html", "ok")
+            }
+            Method::GET => {
+                let _polling = match so.start_poll() {
+                    Some(guard) => guard,
+                    None => return self.reject_overlap(&req, &mut so),
+                };
+                let payload = so.encode_write_buffer(self.config.poll_duration).await;
+                payload_response(&so, payload)
+            }
+            _ => self.reject(&req, Error::BadRequest),
+        }
+    }
+
+    // Closes a socket whose client made a polling request while another of
+    // the same method was in progress, as packets would otherwise be split
+    // between them unpredictably.
+    fn reject_overlap(&self, req: &Request<()>, so: &mut Socket) -> Response<Full<Bytes>> {
+        debug!("overlapping {} request from {}", req.method(), so.id());
+        so.close("transport error");
+        self.reject(req, Error::BadRequest)
+    }
+
+    // Answers a WebSocket handshake, and serves the connection on a new task
+    // once hyper has upgraded it.
+    fn handle_websocket(&self,
+                        mut req: Request<()>,
+                        map: &HashMap<String, String>)
+                        -> Response<Full<Bytes>> {
+        let headers = req.headers();
+        let upgrade = headers.get(header::UPGRADE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.split(',').any(|p| p.trim().eq_ignore_ascii_case("websocket")));
+        let version = headers.get(header::SEC_WEBSOCKET_VERSION).is_some_and(|v| v == "13");
+        // missing when the connection isn't served by hyper, or the stack the
+        // server is mounted in dropped it, in which case it can't be upgraded
+        let upgradable = req.extensions().get::<OnUpgrade>().is_some();
+        let accept = match headers.get(header::SEC_WEBSOCKET_KEY) {
+            Some(key) if req.method() == Method::GET && upgrade && version && upgradable => {
+                derive_accept_key(key.as_bytes())
+            }
+            _ => return self.reject(&req, Error::BadRequest),
+        };
+
+        // the client is upgrading an existing polling socket
+        let upgrading = match map.get("sid") {
+            Some(sid) => {
+                match self.clients.read().unwrap().get(sid) {
+                    Some(so) if so.is_polling() => Some(so.clone()),
+                    _ => return self.reject(&req, Error::InvalidSID),
+                }
+            }
+            None if self.draining() => return status_response(StatusCode::SERVICE_UNAVAILABLE),
+            None => None,
+        };
+        let protocol = match Protocol::from_query(map.get("EIO").map(|v| v.as_str())) {
+            Some(p) => p,
+            None => return self.reject(&req, Error::UnsupportedProtocolVersion),
+        };
+        let session = match upgrading {
+            Some(so) => WsSession::Upgrade(so),
+            None => {
+                let auth = match self.allow_request(&req) {
+                    Ok(auth) => auth,
+                    Err(res) => return res,
+                };
+                let sid = Arc::new((*self.config.generate_id)(&req));
+                let trust_proxy = self.config.trust_proxy;
+                let handshake = handshake(&req, map, protocol, "websocket", trust_proxy);
+                WsSession::Open(sid, handshake, auth)
+            }
+        };
+
+        let mut res = status_response(StatusCode::SWITCHING_PROTOCOLS);
+        let headers = res.headers_mut();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(header::SEC_WEBSOCKET_ACCEPT, HeaderValue::from_str(&accept).unwrap());
+        // clients connecting over WebSocket right away get the cookie as well
+        if let WsSession::Open(ref sid, _, _) = session {
+            self.set_cookie(&mut res, sid);
+        }
+
+        let on_upgrade = hyper::upgrade::on(&mut req);
+        let server = self.clone();
+        tokio::spawn(async move {
+            let ws = match on_upgrade.await {
+                Ok(upgraded) => {
+                    WebSocketStream::from_raw_socket(TokioIo::new(upgraded),
+                                                     Role::Server,
+                                                     Some(server.websocket_config()))
+                        .await
+                }
+                Err(e) => {
+                    debug!("websocket upgrade failed: {}", e);
+                    return;
+                }
+            };
+
+            match session {
+                WsSession::Upgrade(so) => server.upgrade_websocket(so, ws).await,
+                WsSession::Open(sid, handshake, auth) => {
+                    server.open_websocket(sid, handshake, auth, ws).await
+                }
+            }
+        });
+
+        res
+    }
+
+    // Limits messages received over WebSocket to the size of polling request
+    // bodies.
+    fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: Some(self.config.max_http_buffer_size),
+            max_frame_size: Some(self.config.max_http_buffer_size),
+            ..Default::default()
+        }
+    }
+
+    async fn open_websocket<W: WebSocket>(&self,
+                                          sid: Arc<String>,
+                                          handshake: Handshake,
+                                          auth: Option<Auth>,
+                                          ws: W) {
+        debug!("opening new websocket connection {}", sid);
+        let (send, recv) = unbounded_channel();
+        let so = Socket::new(sid.clone(),
+                             Transport::WebSocket(send),
+                             self.clients.clone(),
+                             self.rooms.clone(),
+                             handshake,
+                             false,
+                             None,
+                             self.config.clock.clone())
+            .with_auth(auth);
+        self.clients.write().unwrap().insert(sid.clone(), so.clone());
+        so.emit(self.open_json(sid, &[], so.protocol()));
+
+        for func in self.on_connection.get() {
+            func(so.clone())
+        }
+        self.websocket_loop(so, ws, recv).await;
+    }
+
+    async fn upgrade_websocket<W: WebSocket>(&self, so: Socket, mut ws: W) {
+        match timeout(self.config.upgrade_timeout, self.websocket_probe(&so, &mut ws)).await {
+            Ok(true) => {}
+            Ok(false) => {
+                let _ = ws.close().await;
+                return;
+            }
+            Err(_) => {
+                debug!("upgrade of {} timed out", so.id());
+                let _ = ws.close().await;
+                return;
+            }
+        }
+
+        let (send, recv) = unbounded_channel();
+        so.upgrade(Transport::WebSocket(send)).await;
+        self.websocket_loop(so, ws, recv).await;
+    }
+
+    // Runs the probe exchange of a polling socket being upgraded to `ws`.
+    // Returns true once the client sends the upgrade packet, after which the
+    // socket can be moved over to the WebSocket transport.
+    async fn websocket_probe<W: WebSocket>(&self, so: &Socket, ws: &mut W) -> bool {
+        loop {
+            let packet = match read_packet(ws, so.protocol()).await {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                Err(e) => {
+                    debug!("upgrade of {} failed: {}", so.id(), e);
+                    return false;
+                }
+            };
+
+            match packet.id {
+                ID::Ping if packet.data.as_text() == Some("probe") => {
+                    let pong = Packet {
+                        id: ID::Pong,
+                        data: packet.data,
+                    };
+                    if let Err(e) = write_frames(ws, &[pong], so.protocol()).await {
+                        debug!("upgrade of {} failed: {}", so.id(), e);
+                        return false;
+                    }
+                    // ends the pending poll, so that the client can pause
+                    // the polling transport
+                    so.emit(Packet {
+                        id: ID::Noop,
+                        data: Message::default(),
+                    });
+                }
+                ID::Upgrade => return true,
+                id => {
+                    debug!("upgrade of {} failed: unexpected packet {:?}", so.id(), id);
+                    return false;
+                }
+            }
+        }
+    }
+
+    async fn websocket_loop<W: WebSocket>(&self,
+                                          mut so: Socket,
+                                          ws: W,
+                                          mut recv: UnboundedReceiver<Outgoing>) {
+        let (mut sink, mut stream) = ws.split();
+
+        loop {
+            tokio::select! {
+                packet = read_packet(&mut stream, so.protocol()) => match packet {
+                    Ok(Some(packet)) => {
+                        if self.handle_packet(&mut so, packet) {
+                            break;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        debug!("websocket connection to {} failed: {}", so.id(), e);
+                        if let ReadError::TooLarge = e {
+                            let _ = sink.send(WsMessage::Close(Some(CloseFrame {
+                                code: CloseCode::Size,
+                                reason: e.reason().into(),
+                            }))).await;
+                        }
+                        so.close(e.reason());
+                        break;
+                    }
+                },
+                outgoing = recv.recv() => {
+                    let mut batch = match outgoing {
+                        Some(outgoing) => vec![outgoing],
+                        None => break,
+                    };
+                    while let Ok(outgoing) = recv.try_recv() {
+                        batch.push(outgoing);
+                    }
+                    let (packets, flushed) = split_batch(batch);
+                    if let Err(e) = write_frames(&mut sink, &packets, so.protocol()).await {
+                        debug!("websocket connection to {} failed: {}", so.id(), e);
+                        break;
+                    }
+                    so.call_on_flush(&packets, flushed);
+                }
+                _ = so.wait_closed() => break,
+            }
+
+            if so.closed() {
+                break;
+            }
+        }
+
+        let _ = sink.close().await;
+        if !so.closed() {
+            so.close("transport close");
+        }
+    }
+
+    // Handles a packet sent by the client over any transport. Returns true if
+    // the client asked for the connection to be closed.
+    fn handle_packet(&self, so: &mut Socket, packet: Packet) -> bool {
+        match packet.id {
+            ID::Close => {
+                so.close("close requested by client");
+                return true;
+            }
+            // version 3 clients send pings, while the server sends them to
+            // version 4 clients
+            ID::Ping if so.protocol() == Protocol::V3 => {
+                so.reset_timeout();
+                so.emit(Packet {
+                    id: ID::Pong,
+                    data: packet.data,
+                });
+            }
+            ID::Pong if so.protocol() == Protocol::V4 => so.reset_timeout(),
+            ID::Message => so.call_on_message(&packet.data),
+            _ => {
+                // probe and upgrade packets are only expected on a new
+                // transport, and are handled by `websocket_probe`
+            }
+        }
+        false
+    }
+
+    // Whether new handshakes are refused, as the server is shutting down.
+    fn draining(&self) -> bool {
+        *self.shutdown.borrow() != Shutdown::Running
+    }
+
+    fn start_ping_loop(&self) {
+        if self.ping_loop_started
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok() {
+            let cl = self.clone();
+            tokio::spawn(async move { cl.ping_loop().await });
+        }
+    }
+
+    fn open_connection(&self,
+                       req: &Request<()>,
+                       map: &HashMap<String, String>)
+                       -> Response<Full<Bytes>> {
+        debug!("opening new connection to {:?}", req.extensions().get::<SocketAddr>());
+
+        if req.method() != Method::GET {
+            return self.reject(req, Error::BadHandshake);
+        }
 
         // transport: indicates the transport name. Supported ones by
         // default are polling, flashsocket, websocket.
         let transport = match map.get("transport") {
             Some(s) if s == "polling" => {
-                let (send, recv) = channel();
+                let (send, recv) = unbounded_channel();
                 Transport::Polling(send, Arc::new(Mutex::new(recv)))
             }
-            // add websocket later
+            // websocket connections are opened by `handle_websocket`
             _ => {
-                return make_err!(Error::UnsupportedTransport);
+                return self.reject(req, Error::UnsupportedTransport);
             }
         };
 
         // j: is the transport is polling but a JSONP respose is required, j
         // must be set with the JSONP index.
         let jsonp: Option<_> = match map.get("j") {
-            Some(j) => Some(itry!(i32::from_str_radix(j, 10), status::BadRequest)),
+            Some(j) => {
+                match j.parse::<i32>() {
+                    Ok(j) => Some(j),
+                    Err(_) => return self.reject(req, Error::BadRequest),
+                }
+            }
             None => None,
         };
 
         // b64: if the client doesn't support XHR2, b64=1 is sent in the query
         // string to signal the server that all binary data should be sent base64
         // encoded
-        let b64 = map.get("b64").is_some();
-
-        let sid = match map.get("sid") {
-            Some(s) => {
-                if let Some(so) = self.clients.read().unwrap().get(s) {
-                    so.reset_timeout();
-                    let mut res = Response::new();
-                    let cookie = CookiePair::new(String::from_str("io").unwrap(), s.clone());
-                    res.status = Some(status::Ok);
-                    // TODO: Set cookie path
-                    res.headers.set(SetCookie(vec![cookie]));
-                    return Ok(res);
-                }
-                return make_err!(Error::InvalidSID);
-            }
-            None => Arc::new((*self.config.generate_id)(req)),
+        let b64 = map.contains_key("b64");
+
+        // EIO: the version of the protocol spoken by the client
+        let protocol = match Protocol::from_query(map.get("EIO").map(|v| v.as_str())) {
+            Some(p) => p,
+            None => return self.reject(req, Error::UnsupportedProtocolVersion),
         };
 
+        let auth = match self.allow_request(req) {
+            Ok(auth) => auth,
+            Err(res) => return res,
+        };
+        let sid = Arc::new((*self.config.generate_id)(req));
 
-        let so = Socket::new(sid.clone(), transport, self.clients.clone(), b64, jsonp);
+        let so = Socket::new(sid.clone(),
+                             transport,
+                             self.clients.clone(),
+                             self.rooms.clone(),
+                             handshake(req, map, protocol, "polling", self.config.trust_proxy),
+                             b64,
+                             jsonp,
+                             self.config.clock.clone())
+            .with_auth(auth);
         self.clients.write().unwrap().insert(sid.clone(), so.clone());
-        // if transport is not polling
-        // so.emit(open_json(sid.clone(), Duration::from_secs(2)));
 
-        self.on_connection.read().unwrap().as_ref().map(|func| func(so.clone()));
+        for func in self.on_connection.get() {
+            func(so.clone())
+        }
 
-        let mut res = Response::new();
-        res.status = Some(status::Ok);
-        let cookie = CookiePair::new(String::from_str("io").unwrap(), so.id());
-        // TODO: Set cookie path
-        res.headers.set(SetCookie(vec![cookie]));
-        res.headers.set(Connection(vec![ConnectionOption::KeepAlive]));
-        res.headers.set(Date(HttpDate(time::now())));
-        let mime: Mime = "text/javascript".parse().unwrap();
-        res.headers.set(ContentType(mime));
+        let open = vec![self.open_json(sid.clone(), &["websocket"], protocol)];
+        let payload = match protocol {
+            Protocol::V3 => encode_payload(&open, so.jsonp_index(), so.b64(), so.xhr2()),
+            Protocol::V4 => encode_payload_v4(&open, so.jsonp_index()),
+        };
 
-        // if transport is polling
-        res.body = Some(Box::new(encode_payload(&vec![self.open_json(sid.clone())],
-                                                so.jsonp_index(),
-                                                so.b64(),
-                                                so.xhr2())
-            .0));
-        Ok(res)
+        let mut res = payload_response(&so, payload);
+        self.set_cookie(&mut res, &sid);
+        res
     }
 
-    fn ping_loop(&self) {
-        let data = self.clients.clone();
+    // Sets the cookie holding the client sid on a handshake response, unless
+    // disabled in the config.
+    fn set_cookie(&self, res: &mut Response<Full<Bytes>>, sid: &str) {
+        let config = &self.config;
+        let name = match config.cookie {
+            Some(ref name) => name.clone(),
+            None => return,
+        };
 
-        loop {
-            {
-                let mut map = data.write().unwrap();
-                let timedout_clients: Vec<Arc<String>> = map.iter()
-                    .filter_map(|(sid, so)| {
-                        let instant = so.get_last_pong();
-                        if instant.elapsed().as_secs() * 1000 > 65000 {
-                            // no pong response for < 60000 seconds
-                            Some(sid.clone())
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
+        let mut cookie = Cookie::build((name, sid.to_string()))
+            .http_only(config.cookie_http_only)
+            .secure(config.cookie_secure);
+        if let Some(ref path) = config.cookie_path {
+            cookie = cookie.path(path.clone());
+        }
+        if let Some(ref domain) = config.cookie_domain {
+            cookie = cookie.domain(domain.clone());
+        }
+        if let Some(same_site) = config.cookie_same_site {
+            cookie = cookie.same_site(same_site);
+        }
 
-                for sid in timedout_clients {
-                    map.remove(&sid);
-                }
+        match HeaderValue::from_str(&cookie.to_string()) {
+            Ok(value) => {
+                res.headers_mut().append(header::SET_COOKIE, value);
             }
+            Err(e) => debug!("invalid cookie for {}: {}", sid, e),
+        }
+    }
 
-            {
-                let map = data.read().unwrap();
-                for (_, so) in map.iter() {
-                    let instant = so.get_last_ping();
-                    if instant.elapsed().as_secs() * 1000 > 20000 {
-                        so.emit(Packet {
-                            id: ID::Ping,
-                            data: (b"ping").to_vec(),
-                        });
-                        so.reset_last_ping();
-                    }
-                }
+    async fn ping_loop(&self) {
+        // check often enough to ping clients on time, whatever the interval
+        let mut ticks = interval(self.config.ping_interval.min(Duration::from_secs(1)));
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => self.heartbeat(self.config.clock.now()),
+                _ = self.stopped() => break,
             }
+        }
+    }
+
+    // Closes the sockets that haven't answered a ping (or, for version 3
+    // clients, sent one) in time, and pings version 4 clients that are due.
+    fn heartbeat(&self, now: Instant) {
+        // while shutting down, pings would be queued after the close packet,
+        // and clients are closed by `shutdown` anyway
+        if self.draining() {
+            return;
+        }
+        let timeout = self.config.ping_interval + self.config.ping_timeout;
+        let (timedout, alive): (Vec<Socket>, Vec<Socket>) = self.clients
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .partition(|so| now.saturating_duration_since(so.get_last_pong()) > timeout);
+
+        // the map is no longer locked, as closing removes sockets from it
+        for mut so in timedout {
+            debug!("{} timed out", so.id());
+            so.close("ping timeout");
+        }
 
-            sleep(Duration::from_secs(1));
+        // version 3 clients send pings themselves
+        for so in alive.iter().filter(|so| so.protocol() == Protocol::V4) {
+            if now.saturating_duration_since(so.get_last_ping()) >= self.config.ping_interval {
+                so.emit(Packet {
+                    id: ID::Ping,
+                    data: Message::default(),
+                });
+                so.reset_last_ping();
+            }
         }
     }
 
-    fn open_json(&self, sid: Arc<String>) -> Packet {
-        let s = format!(r#"{{"sid":"{}","upgrades":[],"pingTimeout":{},"pingInterval":{}}}"#,
-                        sid,
-                        self.config.ping_timeout.as_secs() * 1000,
-                        self.config.ping_interval.as_secs() * 1000);
+    fn open_json(&self, sid: Arc<String>, upgrades: &[&str], protocol: Protocol) -> Packet {
+        let upgrades: Vec<String> = upgrades.iter().map(|u| format!(r#""{}""#, u)).collect();
+        let mut s = format!(r#"{{"sid":"{}","upgrades":[{}],"pingTimeout":{},"pingInterval":{}"#,
+                            sid,
+                            upgrades.join(","),
+                            self.config.ping_timeout.as_millis(),
+                            self.config.ping_interval.as_millis());
+        // version 4 clients split their POSTs to stay under this size
+        if protocol == Protocol::V4 {
+            s.push_str(&format!(r#","maxPayload":{}"#, self.config.max_http_buffer_size));
+        }
+        s.push('}');
         Packet {
             id: ID::Open,
-            data: s.into_bytes(),
+            data: Message::Text(s),
         }
     }
 }
 
-fn into_hashmap(pairs: form_urlencoded::ParseIntoOwned) -> HashMap<String, String> {
-    let mut h = HashMap::new();
-    for (q, v) in pairs {
-        h.insert(q, v);
+/// Lets the server be mounted in tower-based frameworks, such as axum, under
+/// any route. Requests are handled as by `Server::handle`, so the client
+/// address must be set as a `SocketAddr` request extension by the framework
+/// for it to be used by `generate_id`, and WebSocket handshakes are refused
+/// unless the request keeps the upgrade extension set by hyper.
+impl<B> Service<Request<B>> for Server
+    where B: Body + Send + 'static,
+          B::Data: Send,
+          B::Error: Into<Box<dyn StdError + Send + Sync>>
+{
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
     }
 
-    h
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let server = self.clone();
+        Box::pin(async move { Ok(server.handle(req).await) })
+    }
 }
 
-impl Handler for Server {
-    fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        if !self.ping_loop_started.clone().compare_and_swap(false, true, Ordering::SeqCst) {
-            let cl = self.clone();
-            spawn(move || cl.ping_loop());
+fn websocket_frame<P: Encode>(packet: &P, protocol: Protocol) -> WsMessage {
+    if packet.packet().is_binary() {
+        // version 4 binary frames can only carry messages, and have no
+        // packet ID
+        WsMessage::Binary(match protocol {
+            Protocol::V3 => packet.encoded_binary().into_owned(),
+            Protocol::V4 => packet.packet().data.as_bytes().to_vec(),
+        })
+    } else {
+        WsMessage::Text(String::from_utf8(packet.encoded().into_owned()).unwrap())
+    }
+}
+
+// Reads the next packet sent by the client, returning `None` for frames that
+// carry no packet.
+async fn read_packet<S>(ws: &mut S, protocol: Protocol) -> Result<Option<Packet>, ReadError>
+    where S: Stream<Item = Result<WsMessage, WsError>> + Unpin
+{
+    let packet = match (ws.next().await, protocol) {
+        (Some(Ok(WsMessage::Text(text))), Protocol::V3) => Packet::from_bytes(text.as_bytes()),
+        (Some(Ok(WsMessage::Text(text))), Protocol::V4) => {
+            Packet::from_bytes_v4(text.as_bytes())
+        }
+        (Some(Ok(WsMessage::Binary(data))), Protocol::V3) => Packet::from_binary(&data),
+        (Some(Ok(WsMessage::Binary(data))), Protocol::V4) => {
+            Ok(Packet {
+                id: ID::Message,
+                data: Message::Binary(data.into()),
+            })
         }
+        (Some(Ok(WsMessage::Close(_))), _) | (None, _) => return Err(ReadError::Closed),
+        // ping and pong frames are answered by tungstenite
+        (Some(Ok(_)), _) => return Ok(None),
+        (Some(Err(WsError::Capacity(_))), _) => return Err(ReadError::TooLarge),
+        (Some(Err(e)), _) => return Err(ReadError::WebSocket(e)),
+    };
 
-        let cookies_raw = match req.headers.get_raw("Cookie") {
-            Some(c) => c,
-            None => return self.open_connection(req),
-        };
-        let mut so: Socket = match self.get_socket(itry!(Cookie::parse_header(cookies_raw))) {
-            Some(so) => so,
-            None => return self.open_connection(req),
+    packet.map(Some).map_err(ReadError::Packet)
+}
+
+// Session served over a new WebSocket connection.
+enum WsSession {
+    // upgrade of an existing polling socket
+    Upgrade(Socket),
+    // new socket, with its id and the data from `Config::allow_request`
+    Open(Arc<String>, Handshake, Option<Auth>),
+}
+
+#[derive(Debug)]
+enum ReadError {
+    Closed,
+    TooLarge,
+    WebSocket(WsError),
+    Packet(PacketError),
+}
+
+impl ReadError {
+    // reason given to `on_close` when the connection is closed because of
+    // this error
+    fn reason(&self) -> &'static str {
+        match *self {
+            ReadError::Closed => "transport close",
+            ReadError::TooLarge => "payload too large",
+            ReadError::WebSocket(_) => "transport error",
+            ReadError::Packet(_) => "parse error",
+        }
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReadError::WebSocket(ref e) => write!(f, "{}: {}", self.reason(), e),
+            ReadError::Packet(ref e) => write!(f, "{}: {}", self.reason(), e),
+            _ => f.write_str(self.reason()),
+        }
+    }
+}
+
+async fn write_frames<S, P>(ws: &mut S, packets: &[P], protocol: Protocol) -> Result<(), WsError>
+    where S: Sink<WsMessage, Error = WsError> + Unpin,
+          P: Encode
+{
+    for packet in packets {
+        ws.feed(websocket_frame(packet, protocol)).await?;
+    }
+    ws.flush().await
+}
+
+// Captures the details of a handshake request.
+fn handshake(req: &Request<()>,
+             map: &HashMap<String, String>,
+             protocol: Protocol,
+             transport: &'static str,
+             trust_proxy: bool)
+             -> Handshake {
+    let headers = req.headers();
+    // the header can be sent by any client, unless a proxy in front of the
+    // server terminates TLS and sets it
+    let secure = req.uri().scheme() == Some(&Scheme::HTTPS) ||
+                 (trust_proxy && headers.get("x-forwarded-proto").is_some_and(|p| p == "https"));
+
+    Handshake {
+        headers: headers.clone(),
+        query: map.clone(),
+        address: req.extensions().get::<SocketAddr>().cloned(),
+        time: SystemTime::now(),
+        protocol,
+        transport,
+        secure,
+    }
+}
+
+// Origin of the page making a request, taken from the `Origin` header, or the
+// `Referer` header if there is none.
+fn request_origin(req: &Request<()>) -> Option<String> {
+    let headers = req.headers();
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        return origin.to_str().ok().map(String::from);
+    }
+
+    let referer: Uri = headers.get(header::REFERER)?.to_str().ok()?.parse().ok()?;
+    let host = referer.host()?;
+    Some(match referer.port() {
+        Some(port) => format!("{}://{}:{}", referer.scheme_str()?, host, port),
+        None => format!("{}://{}", referer.scheme_str()?, host),
+    })
+}
+
+// Answers a CORS preflight request.
+fn preflight(req: &Request<()>, cors: &Cors) -> Response<Full<Bytes>> {
+    let mut res = status_response(StatusCode::NO_CONTENT);
+    let headers = res.headers_mut();
+    headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS,
+                   HeaderValue::from_static("GET, POST, OPTIONS"));
+    if let Some(requested) = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+    }
+    if let Some(max_age) = cors.max_age {
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+    }
+    res
+}
+
+// Sets the CORS headers of a response to a request from `origin`, if the
+// origin is allowed.
+fn set_cors_headers(cors: &Cors, origin: &HeaderValue, headers: &mut HeaderMap) {
+    match origin.to_str() {
+        Ok(o) if cors.origins.allows(o) => {}
+        _ => return,
+    }
+
+    // browsers refuse a wildcard for requests with credentials
+    if let (Origins::Any, false) = (&cors.origins, cors.credentials) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    } else {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+    if cors.credentials {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                       HeaderValue::from_static("true"));
+    }
+    if !cors.exposed_headers.is_empty() {
+        if let Ok(exposed) = HeaderValue::from_str(&cors.exposed_headers.join(", ")) {
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+        }
+    }
+}
+
+fn query_map(req: &Request<()>) -> HashMap<String, String> {
+    let query = req.uri().query().unwrap_or("");
+    form_urlencoded::parse(query.as_bytes()).into_owned().collect()
+}
+
+fn response<T: Into<Bytes>>(status: StatusCode,
+                            content_type: &'static str,
+                            body: T)
+                            -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::new(body.into()));
+    *res.status_mut() = status;
+    res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    res
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::default());
+    *res.status_mut() = status;
+    res
+}
+
+fn payload_response(so: &Socket, payload: Payload) -> Response<Full<Bytes>> {
+    let content_type = if so.jsonp_index().is_some() {
+        "text/javascript; charset=UTF-8"
+    } else if payload.is_binary() {
+        "application/octet-stream"
+    } else {
+        "text/plain; charset=UTF-8"
+    };
+    response(StatusCode::OK, content_type, payload.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant, SystemTime};
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use http_body_util::{BodyExt, Full, StreamBody};
+    use hyper::body::Frame;
+    use hyper::{HeaderMap, Method, Request, StatusCode};
+    use tower_service::Service;
+    use hyper::header::{ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+                        ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+                        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, HeaderName,
+                        ORIGIN, REFERER, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, SET_COOKIE,
+                        UPGRADE,
+                        USER_AGENT};
+    use crate::config::{Allow, Clock, Config, Cors, Origins, SameSite};
+    use futures_util::{stream, SinkExt, StreamExt};
+    use tokio_tungstenite::WebSocketStream;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use crate::packet::{Encode, Message, Protocol};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::sync::mpsc::unbounded_channel;
+    use crate::socket::{Handshake, Socket, Transport};
+    use super::{read_packet, Error, ReadError, Server};
+
+    struct ManualClock(Mutex<Instant>);
+
+    impl ManualClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    async fn handshake(server: &mut Server, eio: u8) -> String {
+        let req = Request::get(format!("/engine.io/?EIO={}&transport=polling", eio))
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = server.call(req).await.unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let start = body.find("\"sid\":\"").unwrap() + 7;
+        body[start..start + 64].to_string()
+    }
+
+    #[tokio::test]
+    async fn service_handshake() {
+        let mut server = Server::new();
+        poll_fn(|cx| Service::<Request<Full<Bytes>>>::poll_ready(&mut server, cx)).await.unwrap();
+
+        let req = Request::get("/engine.io/?EIO=4&transport=polling")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = server.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.starts_with(b"0{\"sid\":"));
+        assert_eq!(server.clients.read().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn heartbeat() {
+        let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+        let mut server = Server::with_config(Config {
+            ping_interval: Duration::from_secs(10),
+            ping_timeout: Duration::from_secs(5),
+            clock: clock.clone(),
+            ..Default::default()
+        });
+        let closed = Arc::new(AtomicBool::new(false));
+        let cl = closed.clone();
+        server.on_connection(move |so| {
+            let closed = cl.clone();
+            so.on_close(move |reason| {
+                assert_eq!(reason, "ping timeout");
+                closed.store(true, Ordering::SeqCst);
+            });
+        });
+        let v3 = handshake(&mut server, 3).await;
+        let v4 = handshake(&mut server, 4).await;
+        let so = server.clients.read().unwrap().get(&v4).cloned().unwrap();
+
+        clock.advance(Duration::from_secs(10));
+        server.heartbeat(clock.now());
+        assert_eq!(so.encode_write_buffer(Duration::from_secs(30)).await.0, b"2");
+
+        // the version 3 client never pinged, while the version 4 client
+        // answered the ping
+        clock.advance(Duration::from_secs(6));
+        so.reset_timeout();
+        server.heartbeat(clock.now());
+        assert!(closed.load(Ordering::SeqCst));
+        assert!(!server.clients.read().unwrap().contains_key(&v3));
+        assert!(server.clients.read().unwrap().contains_key(&v4));
+    }
+
+    #[tokio::test]
+    async fn heartbeat_while_draining() {
+        let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+        let mut server = Server::with_config(Config {
+            ping_interval: Duration::from_secs(10),
+            ping_timeout: Duration::from_secs(5),
+            clock: clock.clone(),
+            ..Default::default()
+        });
+        let sid = handshake(&mut server, 4).await;
+        let so = server.socket(&sid).unwrap();
+
+        let cl = server.clone();
+        let shutdown = tokio::spawn(async move { cl.shutdown(Duration::from_secs(60)).await });
+        tokio::task::yield_now().await;
+        clock.advance(Duration::from_secs(30));
+        server.heartbeat(clock.now());
+        assert!(!so.closed());
+        assert_eq!(so.buffered(), 1);
+
+        assert_eq!(so.encode_write_buffer(Duration::from_secs(30)).await.0, b"1");
+        shutdown.await.unwrap();
+        assert!(so.closed());
+    }
+
+    #[tokio::test]
+    async fn cookie() {
+        let set_cookie = |config: Config| async move {
+            let mut server = Server::with_config(config);
+            let req = Request::get("/engine.io/?EIO=4&transport=polling")
+                .body(Full::new(Bytes::new()))
+                .unwrap();
+            let res = server.call(req).await.unwrap();
+            res.headers().get(SET_COOKIE).map(|c| c.to_str().unwrap().to_string())
         };
 
-        let mut res = Response::new();
-        res.headers.set(Date(HttpDate(time::now())));
-
-        match req.method {
-            Post => {
-                let mut body = Vec::new();
-                itry!(req.body.read_to_end(&mut body));
-                let form = parse(body.as_slice());
-                let mut closing = false;
-
-                for (q, val) in form {
-                    if q == "d" {
-                        // POSTing data
-                        val.replace("\\n", "\n");
-                        match decode_payload(val.into_owned().into_bytes(), so.b64(), so.xhr2()) {
-                            Ok(packets) => {
-                                for packet in packets {
-                                    match packet.id {
-                                        ID::Close => {
-                                            so.close("close requested by client");
-                                            closing = true;
-                                        }
-                                        ID::Pong if packet.data.as_slice() == b"ping" => {
-                                            so.reset_timeout()
-                                        }
-
-                                        ID::Message => so.call_on_message(&packet.data),
-                                        _ => {
-                                            // handle upgrade}
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                let mut res = Response::new();
-                                res.status = Some(status::BadRequest);
-                                return Ok(res);
-                            }
-                        }
-                    }
-                }
+        let cookie = set_cookie(Config {
+            cookie: Some("sess".to_string()),
+            cookie_path: Some("/".to_string()),
+            cookie_domain: Some("example.com".to_string()),
+            cookie_secure: true,
+            cookie_same_site: Some(SameSite::Strict),
+            ..Default::default()
+        }).await.unwrap();
+        assert!(cookie.starts_with("sess="));
+        for attr in &["HttpOnly", "Secure", "SameSite=Strict", "Path=/", "Domain=example.com"] {
+            assert!(cookie.contains(attr), "{} not in {}", attr, cookie);
+        }
 
-                if !closing {
-                    res.headers.set(Connection(vec![ConnectionOption::KeepAlive]));
-                }
+        let cookie = set_cookie(Config {
+            cookie: None,
+            ..Default::default()
+        }).await;
+        assert_eq!(cookie, None);
 
-                let mime: Mime = "text/html".parse().unwrap();
-                res.headers.set(ContentType(mime));
-                res.body = Some(Box::new("ok"));
-                res.status = Some(status::Ok);
+        // sent as well to clients connecting over WebSocket right away
+        let server = Server::new();
+        let (head, _) = websocket_request(&server, "GET", "", &WEBSOCKET_HEADERS).await;
+        assert!(head.starts_with("HTTP/1.1 101"));
+        assert!(head.to_lowercase().contains("\r\nset-cookie: io="), "{}", head);
+    }
 
-                Ok(res)
-            }
-            Get => {
-                let payload = so.encode_write_buffer();
-                let mut res = Response::with(payload);
-                res.status = Some(status::Ok);
-                if so.jsonp_index().is_some() {
-                    let mime: Mime = "text/javascript".parse().unwrap();
-                    res.headers.set(ContentType(mime));
+    #[tokio::test]
+    async fn sid_lookup() {
+        let mut server = Server::new();
+        server.on_connection(|so| {
+            let cl = so.clone();
+            so.on_message(move |m| cl.send(m.clone()));
+        });
+        let sid = handshake(&mut server, 4).await;
+
+        let req = Request::post(format!("/engine.io/?EIO=4&transport=polling&sid={}", sid))
+            .body(Full::new(Bytes::from_static(b"4echo")))
+            .unwrap();
+        assert_eq!(server.call(req).await.unwrap().status(), StatusCode::OK);
+        let req = Request::get(format!("/engine.io/?EIO=4&transport=polling&sid={}", sid))
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = server.call(req).await.unwrap();
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "4echo");
+
+        let req = Request::get("/engine.io/?EIO=4&transport=polling&sid=unknown")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = server.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(),
+                   r#"{"code":1,"message":"Session ID unknown"}"#);
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let mut server = Server::new();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let cl = errors.clone();
+        server.on_connection_error(move |_, e| cl.lock().unwrap().push(e));
+
+        let cases = [
+            (Method::GET, "transport=flashsocket", Error::UnsupportedTransport),
+            (Method::GET, "sid=unknown", Error::UnsupportedTransport),
+            (Method::GET, "transport=polling&sid=unknown", Error::InvalidSID),
+            (Method::POST, "transport=polling", Error::BadHandshake),
+            (Method::GET, "transport=polling&j=x", Error::BadRequest),
+            (Method::GET, "transport=polling&EIO=2", Error::UnsupportedProtocolVersion),
+        ];
+        for &(ref method, query, error) in &cases {
+            let req = Request::builder()
+                .method(method)
+                .uri(format!("/engine.io/?{}", query))
+                .body(Full::new(Bytes::new()))
+                .unwrap();
+            let res = server.call(req).await.unwrap();
+            assert_eq!(res.status(), error.status());
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, format!(r#"{{"code":{},"message":"{}"}}"#, error.code(), error));
+        }
+
+        let expected: Vec<Error> = cases.iter().map(|c| c.2).collect();
+        assert_eq!(*errors.lock().unwrap(), expected);
+        assert_eq!(Error::Forbidden.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn allow_request() {
+        let mut server = Server::with_config(Config {
+            allow_request: Some(Arc::new(|req| {
+                match req.headers().get("authorization") {
+                    Some(token) if token == "secret" => Allow::AcceptWith(Box::new(42u32)),
+                    Some(_) => Allow::Reject(StatusCode::UNAUTHORIZED, "Bad \"token\"".to_string()),
+                    None => Allow::Accept,
                 }
-                res.headers.set(Connection(vec![ConnectionOption::KeepAlive]));
-                Ok(res)
+            })),
+            ..Default::default()
+        });
+        let auth = Arc::new(Mutex::new(vec![]));
+        let cl = auth.clone();
+        server.on_connection(move |so| cl.lock().unwrap().push(so.auth::<u32>().cloned()));
+
+        for token in &[None, Some("secret"), Some("guess")] {
+            let mut req = Request::get("/engine.io/?EIO=4&transport=polling");
+            if let Some(token) = *token {
+                req = req.header("authorization", token);
             }
-            _ => {
-                let mut res = Response::new();
-                res.status = Some(status::MethodNotAllowed);
-                Ok(res)
+            let res = server.call(req.body(Full::new(Bytes::new())).unwrap()).await.unwrap();
+            if token == &Some("guess") {
+                assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+                assert_eq!(res.into_body().collect().await.unwrap().to_bytes(),
+                           r#"{"code":4,"message":"Bad \"token\""}"#);
+            } else {
+                assert_eq!(res.status(), StatusCode::OK);
             }
         }
+        assert_eq!(*auth.lock().unwrap(), vec![None, Some(42)]);
+        assert_eq!(server.clients.read().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn cors() {
+        let mut server = Server::with_config(Config {
+            cors: Some(Cors {
+                origins: Origins::List(vec!["https://example.com".to_string()]),
+                credentials: true,
+                exposed_headers: vec!["x-request-id".to_string()],
+                max_age: Some(Duration::from_secs(600)),
+            }),
+            ..Default::default()
+        });
+
+        let req = Request::options("/engine.io/?EIO=4&transport=polling")
+            .header(ORIGIN, "https://example.com")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = server.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let headers = res.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+
+        let req = Request::get("/engine.io/?EIO=4&transport=polling")
+            .header(ORIGIN, "https://example.com")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = server.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        assert_eq!(res.headers()[ACCESS_CONTROL_EXPOSE_HEADERS], "x-request-id");
+
+        let req = Request::get("/engine.io/?EIO=4&transport=polling")
+            .header(ORIGIN, "https://evil.com")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = server.call(req).await.unwrap();
+        assert!(res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn allowed_origins() {
+        let server = Server::with_config(Config {
+            allowed_origins: Some(Origins::List(vec!["https://*.example.com".to_string()])),
+            ..Default::default()
+        });
+        let call = |query: String, header: Option<(HeaderName, &'static str)>| {
+            let mut req = Request::get(format!("/engine.io/?EIO=4&transport=polling{}", query));
+            if let Some((name, value)) = header {
+                req = req.header(name, value);
+            }
+            let res = server.clone().call(req.body(Full::new(Bytes::new())).unwrap());
+            async move { res.await.unwrap().status() }
+        };
+
+        assert_eq!(call(String::new(), Some((ORIGIN, "https://www.example.com"))).await,
+                   StatusCode::OK);
+        assert_eq!(call(String::new(), Some((REFERER, "https://a.example.com/chat?room=1"))).await,
+                   StatusCode::OK);
+        assert_eq!(call(String::new(), None).await, StatusCode::OK);
+        assert_eq!(call("&j=0".to_string(), None).await, StatusCode::FORBIDDEN);
+        assert_eq!(call(String::new(), Some((ORIGIN, "https://evil.com"))).await,
+                   StatusCode::FORBIDDEN);
+
+        // later requests of a session are checked as well
+        let sid = server.clients.read().unwrap().keys().next().unwrap().to_string();
+        assert_eq!(call(format!("&sid={}", sid), Some((ORIGIN, "https://evil.com"))).await,
+                   StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn max_http_buffer_size() {
+        let mut server = Server::with_config(Config {
+            max_http_buffer_size: 16,
+            ..Default::default()
+        });
+        let reason = Arc::new(Mutex::new(None));
+        let cl = reason.clone();
+        server.on_connection(move |so| {
+            let reason = cl.clone();
+            so.on_close(move |r| *reason.lock().unwrap() = Some(r.to_string()));
+        });
+        let req = Request::get("/engine.io/?EIO=4&transport=polling")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = server.call(req).await.unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let open = String::from_utf8(body.to_vec()).unwrap();
+        assert!(open.ends_with(r#","maxPayload":16}"#));
+        let sid = &open[9..73];
+
+        let req = Request::post(format!("/engine.io/?EIO=4&transport=polling&sid={}", sid))
+            .body(Full::new(Bytes::from(vec![b'4'; 17])))
+            .unwrap();
+        let res = server.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(reason.lock().unwrap().as_deref(), Some("payload too large"));
+
+        let (client, conn) = tokio::io::duplex(1024);
+        let config = server.websocket_config();
+        let mut ws = WebSocketStream::from_raw_socket(conn, Role::Server, Some(config)).await;
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        client.send(WsMessage::Text("4".repeat(17))).await.unwrap();
+        match read_packet(&mut ws, Protocol::V4).await {
+            Err(ReadError::TooLarge) => {}
+            res => panic!("expected the message to be too large, got {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_metadata() {
+        let mut server = Server::with_config(Config {
+            trust_proxy: true,
+            ..Default::default()
+        });
+        let handshake = Arc::new(Mutex::new(None));
+        let cl = handshake.clone();
+        server.on_connection(move |so| *cl.lock().unwrap() = Some(so.handshake().clone()));
+
+        let addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let mut req = Request::get("/engine.io/?EIO=3&transport=polling&token=abc")
+            .header(USER_AGENT, "test")
+            .header("x-forwarded-proto", "https")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        req.extensions_mut().insert(addr);
+        server.call(req).await.unwrap();
+
+        let handshake = handshake.lock().unwrap().take().unwrap();
+        assert_eq!(handshake.headers[USER_AGENT], "test");
+        assert_eq!(handshake.query["token"], "abc");
+        assert_eq!(handshake.address, Some(addr));
+        assert_eq!(handshake.protocol, Protocol::V3);
+        assert_eq!(handshake.transport, "polling");
+        assert!(handshake.secure);
+
+        // the header is ignored unless the server is behind a trusted proxy
+        let mut server = Server::new();
+        let cl = server.clone();
+        let accepted = tokio::spawn(async move { cl.accept().await });
+        tokio::task::yield_now().await;
+        let req = Request::get("/engine.io/?EIO=4&transport=polling")
+            .header("x-forwarded-proto", "https")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        server.call(req).await.unwrap();
+        assert!(!accepted.await.unwrap().unwrap().handshake().secure);
+    }
+
+    #[tokio::test]
+    async fn accept() {
+        let mut server = Server::new();
+        let cl = server.clone();
+        let accepted = tokio::spawn(async move { cl.accept().await.map(|so| so.id()) });
+        // lets the task start waiting
+        tokio::task::yield_now().await;
+
+        let sid = handshake(&mut server, 4).await;
+        assert_eq!(accepted.await.unwrap(), Some(sid));
+    }
+
+    #[tokio::test]
+    async fn rooms() {
+        let mut server = Server::new();
+        let mut sids = vec![];
+        for _ in 0..3 {
+            sids.push(handshake(&mut server, 4).await);
+        }
+        let sockets: Vec<Socket> = {
+            let clients = server.clients.read().unwrap();
+            sids.iter().map(|sid| clients.get(sid).cloned().unwrap()).collect()
+        };
+        sockets[0].join("a");
+        sockets[1].join("a");
+        sockets[1].join("b");
+        assert_eq!(sockets[1].rooms().len(), 2);
+
+        server.to("a").to("b").send("ab");
+        server.broadcast("all");
+        sockets[0].broadcast().to("a").send(b"others".to_vec());
+        sockets[1].leave("a");
+        server.to("a").except(&sids[0]).send("nobody");
+
+        let mut payloads = vec![];
+        for so in &sockets {
+            payloads.push(so.encode_write_buffer(Duration::from_secs(30)).await.0);
+        }
+        assert_eq!(payloads,
+                   vec![b"4ab\x1e4all".to_vec(),
+                        b"4ab\x1e4all\x1ebb3RoZXJz".to_vec(),
+                        b"4all".to_vec()]);
+
+        let mut so = sockets[1].clone();
+        so.close("test");
+        assert!(server.rooms.read().unwrap().get("b").is_none());
+        so.join("b");
+        assert!(so.rooms().is_empty());
+    }
+
+    #[tokio::test]
+    async fn broadcast_shares_encoding() {
+        let mut server = Server::new();
+        let mut queues = vec![];
+        for _ in 0..2 {
+            let sid = handshake(&mut server, 4).await;
+            let so = server.socket(&sid).unwrap();
+            let (send, recv) = unbounded_channel();
+            so.upgrade(Transport::WebSocket(send)).await;
+            queues.push(recv);
+        }
+
+        server.broadcast("shared");
+        let packets: Vec<_> = queues.iter_mut()
+            .map(|recv| recv.try_recv().unwrap().packet)
+            .collect();
+        let (a, b) = (packets[0].encoded(), packets[1].encoded());
+        assert_eq!(&*a, b"4shared");
+        assert_eq!(a.as_ptr(), b.as_ptr());
+    }
+
+    #[tokio::test]
+    async fn registry() {
+        let mut server = Server::new();
+        let reasons = Arc::new(Mutex::new(vec![]));
+        let cl = reasons.clone();
+        server.on_connection(move |so| {
+            let cl = cl.clone();
+            so.on_close(move |reason| cl.lock().unwrap().push(reason.to_string()));
+        });
+        let first = handshake(&mut server, 4).await;
+        let second = handshake(&mut server, 3).await;
+
+        assert_eq!(server.clients_count(), 2);
+        assert_eq!(server.socket(&first).map(|so| so.id()), Some(first.clone()));
+        assert!(server.socket("unknown").is_none());
+        let mut sids: Vec<String> = server.sockets().iter().map(|so| so.id()).collect();
+        sids.sort();
+        let mut expected = vec![first.clone(), second.clone()];
+        expected.sort();
+        assert_eq!(sids, expected);
+
+        assert!(server.close_socket(&first, "kicked"));
+        assert!(!server.close_socket(&first, "kicked"));
+        assert_eq!(server.clients_count(), 1);
+        assert!(server.socket(&first).is_none());
+        assert_eq!(*reasons.lock().unwrap(), vec!["kicked".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let mut server = Server::new();
+        let polling = handshake(&mut server, 4).await;
+        let idle = handshake(&mut server, 4).await;
+
+        let mut cl = server.clone();
+        let poll = tokio::spawn(async move {
+            let req = Request::get(format!("/engine.io/?EIO=4&transport=polling&sid={}", polling))
+                .body(Full::new(Bytes::new()))
+                .unwrap();
+            let res = cl.call(req).await.unwrap();
+            res.into_body().collect().await.unwrap().to_bytes()
+        });
+        tokio::task::yield_now().await;
+
+        let closed = server.socket(&idle).unwrap();
+        server.shutdown(Duration::from_millis(50)).await;
+        assert_eq!(poll.await.unwrap(), "1");
+        assert!(closed.closed());
+        assert_eq!(server.clients_count(), 0);
+
+        let req = Request::get("/engine.io/?EIO=4&transport=polling")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = server.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        // returns right away once shut down
+        server.shutdown(Duration::from_secs(60)).await;
+    }
+
+    #[tokio::test]
+    async fn poll_release() {
+        let mut server = Server::with_config(Config {
+            poll_duration: Duration::from_millis(20),
+            ..Default::default()
+        });
+        let sid = handshake(&mut server, 4).await;
+        let poll = |mut server: Server, sid: String| async move {
+            let req = Request::get(format!("/engine.io/?EIO=4&transport=polling&sid={}", sid))
+                .body(Full::new(Bytes::new()))
+                .unwrap();
+            let res = server.call(req).await.unwrap();
+            res.into_body().collect().await.unwrap().to_bytes()
+        };
+
+        assert_eq!(poll(server.clone(), sid.clone()).await, "6");
+
+        let pending = tokio::spawn(poll(server.clone(), sid.clone()));
+        tokio::task::yield_now().await;
+        server.close_socket(&sid, "kicked");
+        assert_eq!(pending.await.unwrap(), "1");
+    }
+
+    #[tokio::test]
+    async fn overlapping_requests() {
+        let mut server = Server::new();
+        let reasons = Arc::new(Mutex::new(vec![]));
+        let cl = reasons.clone();
+        server.on_connection(move |so| {
+            let cl = cl.clone();
+            so.on_close(move |reason| cl.lock().unwrap().push(reason.to_string()));
+        });
+
+        let sid = handshake(&mut server, 4).await;
+        let uri = format!("/engine.io/?EIO=4&transport=polling&sid={}", sid);
+        let mut cl = server.clone();
+        let get = Request::get(&uri).body(Full::new(Bytes::new())).unwrap();
+        let first = tokio::spawn(async move {
+            let res = cl.call(get).await.unwrap();
+            res.into_body().collect().await.unwrap().to_bytes()
+        });
+        tokio::task::yield_now().await;
+
+        let get = Request::get(&uri).body(Full::new(Bytes::new())).unwrap();
+        assert_eq!(server.call(get).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(first.await.unwrap(), "1");
+        assert!(server.socket(&sid).is_none());
+
+        // a POST whose body never arrives stays in progress
+        let sid = handshake(&mut server, 4).await;
+        let uri = format!("/engine.io/?EIO=4&transport=polling&sid={}", sid);
+        let mut cl = server.clone();
+        let body = StreamBody::new(stream::pending::<Result<Frame<Bytes>, Infallible>>());
+        let post = Request::post(&uri).body(body).unwrap();
+        let first = tokio::spawn(async move { cl.call(post).await });
+        tokio::task::yield_now().await;
+
+        let post = Request::post(&uri).body(Full::new(Bytes::from_static(b"4a"))).unwrap();
+        assert_eq!(server.call(post).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert!(server.socket(&sid).is_none());
+        first.abort();
+        assert_eq!(*reasons.lock().unwrap(),
+                   vec!["transport error".to_string(), "transport error".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn accept_buffers_messages() {
+        let mut server = Server::new();
+        let cl = server.clone();
+        let accepted = tokio::spawn(async move { cl.accept().await });
+        tokio::task::yield_now().await;
+
+        let sid = handshake(&mut server, 4).await;
+        let so = accepted.await.unwrap().unwrap();
+        let req = Request::post(format!("/engine.io/?EIO=4&transport=polling&sid={}", sid))
+            .body(Full::new(Bytes::from_static(b"4early")))
+            .unwrap();
+        assert_eq!(server.call(req).await.unwrap().status(), StatusCode::OK);
+
+        let mut messages = so.messages();
+        assert_eq!(messages.next().await, Some(Message::from("early")));
+    }
+
+    // Connects a client to a WebSocket served over an in-memory connection.
+    const WEBSOCKET_HEADERS: [&str; 3] = ["Upgrade: websocket",
+                                          "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==",
+                                          "Sec-WebSocket-Version: 13"];
+
+    // Sends a WebSocket handshake with `headers` to `server` over an in-memory
+    // connection served as by `listen`. Returns the head of the response,
+    // and the connection to use once upgraded.
+    async fn websocket_request(server: &Server,
+                               method: &str,
+                               query: &str,
+                               headers: &[&str])
+                               -> (String, DuplexStream) {
+        let (mut client, conn) = duplex(4096);
+        let cl = server.clone();
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        tokio::spawn(async move { cl.serve_connection(conn, addr).await });
+
+        let mut req = format!("{} /engine.io/?EIO=4&transport=websocket{} HTTP/1.1\r\n\
+                               Host: localhost\r\nConnection: Upgrade\r\n",
+                              method,
+                              query);
+        for header in headers {
+            req.push_str(header);
+            req.push_str("\r\n");
+        }
+        req.push_str("\r\n");
+        client.write_all(req.as_bytes()).await.unwrap();
+
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.unwrap());
+        }
+        (String::from_utf8(head).unwrap(), client)
+    }
+
+    async fn websocket_pair() -> (WebSocketStream<DuplexStream>, WebSocketStream<DuplexStream>) {
+        let (client, server) = duplex(4096);
+        (WebSocketStream::from_raw_socket(client, Role::Client, None).await,
+         WebSocketStream::from_raw_socket(server, Role::Server, None).await)
+    }
+
+    #[tokio::test]
+    async fn websocket() {
+        let server = Server::new();
+        let reason = Arc::new(Mutex::new(None));
+        let cl = reason.clone();
+        server.on_connection(move |so| {
+            let echo = so.clone();
+            so.on_message(move |m| echo.send(m.clone()));
+            let cl = cl.clone();
+            so.on_close(move |r| *cl.lock().unwrap() = Some(r.to_string()));
+        });
+
+        let (mut client, ws) = websocket_pair().await;
+        let handshake = Handshake {
+            headers: HeaderMap::new(),
+            query: HashMap::new(),
+            address: None,
+            time: SystemTime::now(),
+            protocol: Protocol::V4,
+            transport: "websocket",
+            secure: false,
+        };
+        let cl = server.clone();
+        let served = tokio::spawn(async move {
+            cl.open_websocket(Arc::new("ws".to_string()), handshake, None, ws).await
+        });
+
+        match client.next().await {
+            Some(Ok(WsMessage::Text(open))) => assert!(open.starts_with(r#"0{"sid":"ws","#)),
+            res => panic!("expected the open packet, got {:?}", res),
+        }
+        client.send(WsMessage::Text("4hello".to_string())).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(),
+                   WsMessage::Text("4hello".to_string()));
+        client.send(WsMessage::Binary(vec![0xff, 0x00])).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), WsMessage::Binary(vec![0xff, 0x00]));
+
+        client.send(WsMessage::Text("1".to_string())).await.unwrap();
+        served.await.unwrap();
+        assert_eq!(*reason.lock().unwrap(), Some("close requested by client".to_string()));
+        assert!(server.socket("ws").is_none());
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrade() {
+        let mut server = Server::new();
+        let sid = handshake(&mut server, 4).await;
+        let so = server.socket(&sid).unwrap();
+
+        let (mut client, ws) = websocket_pair().await;
+        let (cl, upgraded) = (server.clone(), so.clone());
+        let served = tokio::spawn(async move { cl.upgrade_websocket(upgraded, ws).await });
+
+        client.send(WsMessage::Text("2probe".to_string())).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(),
+                   WsMessage::Text("3probe".to_string()));
+        // the pending poll is ended with a noop
+        assert_eq!(so.encode_write_buffer(Duration::from_secs(30)).await.0, b"6");
+        client.send(WsMessage::Text("5".to_string())).await.unwrap();
+        while so.is_polling() {
+            tokio::task::yield_now().await;
+        }
+
+        so.send("upgraded");
+        assert_eq!(client.next().await.unwrap().unwrap(),
+                   WsMessage::Text("4upgraded".to_string()));
+
+        server.close_socket(&sid, "test");
+        served.await.unwrap();
+        match client.next().await {
+            Some(Ok(WsMessage::Close(_))) | None => {}
+            res => panic!("expected the connection to be closed, got {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_handshake() {
+        let ids = Arc::new(AtomicUsize::new(0));
+        let cl = ids.clone();
+        let mut server = Server::with_config(Config {
+            generate_id: Arc::new(move |_| {
+                format!("{:064}", cl.fetch_add(1, Ordering::SeqCst))
+            }),
+            ..Default::default()
+        });
+        let sid = handshake(&mut server, 4).await;
+        let query = format!("&sid={}", sid);
+
+        for missing in 0..WEBSOCKET_HEADERS.len() {
+            let mut headers = WEBSOCKET_HEADERS.to_vec();
+            headers.remove(missing);
+            let (head, _) = websocket_request(&server, "GET", &query, &headers).await;
+            assert!(head.starts_with("HTTP/1.1 400"), "without {}: {}", missing, head);
+        }
+        let mut headers = WEBSOCKET_HEADERS;
+        headers[2] = "Sec-WebSocket-Version: 8";
+        let (head, _) = websocket_request(&server, "GET", &query, &headers).await;
+        assert!(head.starts_with("HTTP/1.1 400"));
+        let (head, _) = websocket_request(&server, "POST", &query, &WEBSOCKET_HEADERS).await;
+        assert!(head.starts_with("HTTP/1.1 400"));
+
+        // requests that can't be upgraded, not being served by hyper
+        let req = Request::get(format!("/engine.io/?EIO=4&transport=websocket{}", query))
+            .header(UPGRADE, "WebSocket")
+            .header(SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = server.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let (head, _) = websocket_request(&server, "GET", &query, &WEBSOCKET_HEADERS).await;
+        assert!(head.starts_with("HTTP/1.1 101"));
+        // upgrades keep the id of their socket
+        assert_eq!(ids.load(Ordering::SeqCst), 1);
+
+        let (head, conn) = websocket_request(&server, "GET", "", &WEBSOCKET_HEADERS).await;
+        assert!(head.starts_with("HTTP/1.1 101"));
+        let mut client = WebSocketStream::from_raw_socket(conn, Role::Client, None).await;
+        match client.next().await {
+            Some(Ok(WsMessage::Text(open))) => assert!(open.starts_with(r#"0{"sid":"#)),
+            res => panic!("expected the open packet, got {:?}", res),
+        }
+        assert_eq!(ids.load(Ordering::SeqCst), 2);
     }
 }
@@ -1,76 +1,239 @@
-use std::time::Instant;
-use std::sync::{RwLock, Mutex, Arc};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use std::net::SocketAddr;
+use std::any::{Any, TypeId};
+use std::sync::{RwLock, Arc};
 use std::collections::HashMap;
-use std::sync::mpsc::{Sender, Receiver};
+use std::mem::{replace, take};
+use std::pin::Pin;
+use std::sync::Mutex as StdMutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
 
-use packet::{Packet, encode_payload, Payload, ID};
+use hyper::HeaderMap;
+use log::debug;
+use tokio::sync::{oneshot, watch, Mutex};
+use futures_util::{ready, Sink, Stream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender, UnboundedReceiver};
+use tokio::time::sleep;
+
+use crate::broadcast::{Broadcast, Clients, Rooms};
+use crate::config::Clock;
+use crate::listener::{Handle, Listeners};
+use crate::packet::{Message, Packet, Payload, Protocol, SharedPacket, ID, Error};
+use crate::packet::{encode_payload, encode_payload_v4, decode_payload, decode_payload_v4};
 
 #[derive(Clone)]
 #[doc(hidden)]
 pub enum Transport {
-    Polling(Sender<Packet>, Arc<Mutex<Receiver<Packet>>>),
+    Polling(UnboundedSender<Outgoing>, Arc<Mutex<UnboundedReceiver<Outgoing>>>),
+    WebSocket(UnboundedSender<Outgoing>),
+}
+
+/// Packet waiting to be written to the transport, along with the sender to
+/// notify once it is, for packets sent by `Socket::send_flushed`.
+#[doc(hidden)]
+pub struct Outgoing {
+    pub packet: SharedPacket,
+    pub flushed: Option<oneshot::Sender<()>>,
+}
+
+impl From<Packet> for Outgoing {
+    fn from(packet: Packet) -> Outgoing {
+        Outgoing {
+            packet: packet.into(),
+            flushed: None,
+        }
+    }
+}
+
+/// Details of the request that opened a socket
+#[derive(Clone, Debug)]
+pub struct Handshake {
+    /// Headers of the request
+    pub headers: HeaderMap,
+    /// Query parameters of the request
+    pub query: HashMap<String, String>,
+    /// Address of the client, if known
+    pub address: Option<SocketAddr>,
+    /// Time at which the socket was opened
+    pub time: SystemTime,
+    /// Version of the engine.io protocol spoken by the client
+    pub protocol: Protocol,
+    /// Name of the transport the socket was opened with, `polling` or
+    /// `websocket`
+    pub transport: &'static str,
+    /// Whether the request was made over HTTPS, as told by the request URI,
+    /// or the `X-Forwarded-Proto` header if `Config::trust_proxy` is set
+    pub secure: bool,
+}
+
+/// Map holding one value of each type, used to attach application data to a
+/// socket.
+#[derive(Clone, Default)]
+pub struct Extensions(Arc<RwLock<HashMap<TypeId, Box<dyn Any + Send + Sync>>>>);
+
+impl Extensions {
+    /// Insert a value, returning the previous value of the same type
+    pub fn insert<T: Any + Send + Sync>(&self, val: T) -> Option<T> {
+        self.0
+            .write()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Box::new(val))
+            .and_then(|prev| prev.downcast().ok())
+            .map(|prev| *prev)
+    }
+
+    /// Get a copy of the value of type `T`
+    pub fn get<T: Any + Clone>(&self) -> Option<T> {
+        self.0
+            .read()
+            .unwrap()
+            .get(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_ref())
+            .cloned()
+    }
+
+    /// Call `f` on the value of type `T`, returning its result
+    pub fn with<T: Any, R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Option<R> {
+        self.0
+            .write()
+            .unwrap()
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_mut())
+            .map(f)
+    }
+
+    /// Remove the value of type `T`, returning it
+    pub fn remove<T: Any>(&self) -> Option<T> {
+        self.0
+            .write()
+            .unwrap()
+            .remove(&TypeId::of::<T>())
+            .and_then(|val| val.downcast().ok())
+            .map(|val| *val)
+    }
+
+    pub fn contains<T: Any>(&self) -> bool {
+        self.0.read().unwrap().contains_key(&TypeId::of::<T>())
+    }
+
+    fn clear(&self) {
+        // values are dropped once the lock is released, in case they refer
+        // back to the socket
+        let values = take(&mut *self.0.write().unwrap());
+        drop(values);
+    }
 }
 
 #[derive(Clone)]
 pub struct Socket {
-    transport: Transport,
+    transport: Arc<RwLock<Transport>>,
     sid: Arc<String>,
     last_pong: Arc<RwLock<Instant>>,
     last_ping: Arc<RwLock<Instant>>,
-    closed: Arc<AtomicBool>,
+    clock: Arc<dyn Clock>,
+    auth: Option<Arc<dyn Any + Send + Sync>>,
+    handshake: Arc<Handshake>,
+    extensions: Extensions,
+    buffered: Arc<watch::Sender<usize>>,
+    closed: Arc<watch::Sender<bool>>,
+    early_messages: Arc<StdMutex<Option<Messages>>>,
+    polling: Arc<AtomicBool>,
+    posting: Arc<AtomicBool>,
+    protocol: Protocol,
     b64: bool,
     xhr2: bool,
     jsonp: Option<i32>,
-    client_map: Arc<RwLock<HashMap<Arc<String>, Socket>>>,
-    on_close: Arc<RwLock<Option<Box<Fn(&str) + 'static>>>>,
-    on_message: Arc<RwLock<Option<Box<Fn(&[u8]) + 'static>>>>,
-    on_packet: Arc<RwLock<Option<Box<Fn(Packet) + 'static>>>>,
-    on_flush: Arc<RwLock<Option<Box<Fn(&[Packet]) + 'static>>>>,
+    client_map: Clients,
+    rooms: Rooms,
+    on_close: Listeners<Callback<str>>,
+    on_message: Listeners<Callback<Message>>,
+    on_packet: Listeners<dyn Fn(Packet) + Send + Sync>,
+    on_flush: Listeners<Callback<[Packet]>>,
 }
 
-unsafe impl Send for Socket {}
-unsafe impl Sync for Socket {}
+type Callback<T> = dyn Fn(&T) + Send + Sync;
 
 impl Socket {
     #[doc(hidden)]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(sid: Arc<String>,
                transport: Transport,
-               client_map: Arc<RwLock<HashMap<Arc<String>, Socket>>>,
+               client_map: Clients,
+               rooms: Rooms,
+               handshake: Handshake,
                b64: bool,
-               jsonp: Option<i32>)
+               jsonp: Option<i32>,
+               clock: Arc<dyn Clock>)
                -> Socket {
+        let now = clock.now();
         Socket {
-            transport: transport,
-            sid: sid,
-            last_pong: Arc::new(RwLock::new(Instant::now())),
-            last_ping: Arc::new(RwLock::new(Instant::now())),
-            closed: Arc::new(AtomicBool::new(false)),
-            b64: b64,
-            jsonp: jsonp,
+            transport: Arc::new(RwLock::new(transport)),
+            sid,
+            last_pong: Arc::new(RwLock::new(now)),
+            last_ping: Arc::new(RwLock::new(now)),
+            clock,
+            auth: None,
+            closed: Arc::new(watch::Sender::new(false)),
+            early_messages: Arc::new(StdMutex::new(None)),
+            polling: Arc::new(AtomicBool::new(false)),
+            posting: Arc::new(AtomicBool::new(false)),
+            protocol: handshake.protocol,
+            handshake: Arc::new(handshake),
+            extensions: Extensions::default(),
+            buffered: Arc::new(watch::Sender::new(0)),
+            b64,
+            jsonp,
             xhr2: !b64,
-            client_map: client_map,
-            on_close: Arc::new(RwLock::new(None)),
-            on_message: Arc::new(RwLock::new(None)),
-            on_packet: Arc::new(RwLock::new(None)),
-            on_flush: Arc::new(RwLock::new(None)),
+            client_map,
+            rooms,
+            on_close: Listeners::new(),
+            on_message: Listeners::new(),
+            on_packet: Listeners::new(),
+            on_flush: Listeners::new(),
         }
     }
 
     pub fn id(&self) -> String {
-        self.sid.clone().as_str().to_string()
+        self.sid.as_str().to_string()
+    }
+
+    #[doc(hidden)]
+    pub fn with_auth(mut self, auth: Option<Box<dyn Any + Send + Sync>>) -> Socket {
+        self.auth = auth.map(Arc::from);
+        self
+    }
+
+    /// Details of the request that opened the socket
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    /// Application data attached to the socket, shared by all its clones and
+    /// dropped when it is closed
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Data attached to the socket by `Config::allow_request`, if it is of
+    /// type `T`
+    pub fn auth<T: Any>(&self) -> Option<&T> {
+        self.auth.as_ref().and_then(|auth| auth.downcast_ref())
     }
 
     #[doc(hidden)]
     pub fn reset_timeout(&self) {
-        *self.last_pong.write().unwrap() = Instant::now();
+        *self.last_pong.write().unwrap() = self.clock.now();
     }
 
     #[doc(hidden)]
     pub fn reset_last_ping(&self) {
         let data = self.last_ping.clone();
         let mut instant = data.write().unwrap();
-        *instant = Instant::now();
+        *instant = self.clock.now();
     }
 
     pub fn get_last_pong(&self) -> Instant {
@@ -80,11 +243,36 @@ impl Socket {
     }
 
     pub fn get_last_ping(&self) -> Instant {
-        let data = self.last_pong.clone();
+        let data = self.last_ping.clone();
         let instant = data.read().unwrap();
         *instant
     }
 
+    /// Mark a polling GET request as in progress until the returned guard is
+    /// dropped. Returns `None` if the client already has one in progress.
+    #[doc(hidden)]
+    pub fn start_poll(&self) -> Option<RequestGuard> {
+        RequestGuard::new(&self.polling)
+    }
+
+    /// Same as `start_poll`, for POST requests
+    #[doc(hidden)]
+    pub fn start_post(&self) -> Option<RequestGuard> {
+        RequestGuard::new(&self.posting)
+    }
+
+    #[inline(always)]
+    #[doc(hidden)]
+    pub fn is_polling(&self) -> bool {
+        matches!(*self.transport.read().unwrap(), Transport::Polling(..))
+    }
+
+    /// Version of the engine.io protocol spoken by the client
+    #[inline(always)]
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
     #[inline(always)]
     pub fn b64(&self) -> bool {
         self.b64
@@ -102,76 +290,295 @@ impl Socket {
 
     #[inline(always)]
     pub fn close(&mut self, reason: &str) {
-        self.closed.store(true, Ordering::Relaxed);
-        let data = self.client_map.clone();
-        let mut map = data.write().unwrap();
-        map.remove(&self.sid);
-        self.on_close.read().unwrap().as_ref().map(|f| f(reason));
+        if self.closed.send_replace(true) {
+            return;
+        }
+        self.client_map.write().unwrap().remove(&self.sid);
+        for func in self.on_close.get() {
+            func(reason)
+        }
+        // after the callback, which may still need the data
+        self.leave_all();
+        self.extensions.clear();
     }
 
     #[inline(always)]
     pub fn closed(&self) -> bool {
-        self.closed.load(Ordering::Relaxed)
+        *self.closed.borrow()
+    }
+
+    /// Wait for the socket to be closed.
+    #[doc(hidden)]
+    pub async fn wait_closed(&self) {
+        let mut closed = self.closed.subscribe();
+        // the sender lives as long as this socket
+        let _ = closed.wait_for(|closed| *closed).await;
     }
 
     #[doc(hidden)]
     pub fn emit(&self, data: Packet) {
-        if self.closed.load(Ordering::Relaxed) {
+        self.queue(data.into())
+    }
+
+    /// Queue a packet whose encodings are shared with other sockets
+    #[doc(hidden)]
+    pub fn emit_shared(&self, packet: SharedPacket) {
+        self.queue(Outgoing {
+            packet,
+            flushed: None,
+        })
+    }
+
+    // Queues a packet on the current transport. If the socket is closed, it
+    // is dropped along with its `flushed` sender.
+    fn queue(&self, data: Outgoing) {
+        if self.closed() {
             return;
         }
-        debug!("sending ID {:?}", data.id);
-        match self.transport {
-            Transport::Polling(ref send, _) => send.send(data).unwrap(),
+        debug!("sending ID {:?}", data.packet.id);
+        let transport = self.transport.read().unwrap();
+        let send = match *transport {
+            Transport::Polling(ref send, _) => send,
+            Transport::WebSocket(ref send) => send,
+        };
+        // counted before sending, as the packet may be flushed right away
+        self.buffered.send_modify(|n| *n += 1);
+        if let Err(e) = send.send(data) {
+            debug!("dropping packet for {}: {}", self.sid, e);
+            self.unbuffer(1);
+        }
+    }
+
+    fn unbuffer(&self, n: usize) {
+        self.buffered.send_modify(|buffered| *buffered = buffered.saturating_sub(n));
+    }
+
+    /// Number of packets waiting to be sent to the client
+    pub fn buffered(&self) -> usize {
+        *self.buffered.borrow()
+    }
+
+    /// Wait until fewer than `n` packets are waiting to be sent, returning
+    /// false if the socket was closed first.
+    #[doc(hidden)]
+    pub async fn wait_buffered_below(&self, n: usize) -> bool {
+        let mut buffered = self.buffered.subscribe();
+        tokio::select! {
+            _ = buffered.wait_for(|buffered| *buffered < n) => !self.closed(),
+            _ = self.wait_closed() => false,
+        }
+    }
+
+    /// Switch the socket over to `transport`. Packets still buffered for a
+    /// polling transport are moved over to the new transport, ahead of any
+    /// packet sent during the upgrade.
+    #[doc(hidden)]
+    pub async fn upgrade(&self, transport: Transport) {
+        let send = match transport {
+            Transport::Polling(ref send, _) => send.clone(),
+            Transport::WebSocket(ref send) => send.clone(),
+        };
+        // packets sent while the polling queue is being drained are held
+        // back, so that they reach the new transport after it
+        let (held_send, mut held) = unbounded_channel();
+        let old = {
+            let mut current = self.transport.write().unwrap();
+            replace(&mut *current, Transport::WebSocket(held_send))
+        };
+
+        if let Transport::Polling(old_send, lock) = old {
+            // wakes up a GET waiting for packets, if any
+            drop(old_send);
+            let mut recv = lock.lock().await;
+            while let Ok(outgoing) = recv.try_recv() {
+                if outgoing.packet.id == ID::Noop {
+                    self.unbuffer(1);
+                } else {
+                    self.forward(&send, outgoing);
+                }
+            }
+        }
+
+        // no packet can be queued while the write lock is held
+        let mut current = self.transport.write().unwrap();
+        *current = transport;
+        while let Ok(outgoing) = held.try_recv() {
+            self.forward(&send, outgoing);
         }
+        debug!("upgraded {} to a new transport", self.sid);
     }
 
-    /// Send a message to the client
-    pub fn send(&self, data: Vec<u8>) {
-        self.emit(Packet{
+    // Moves an already counted packet over to another queue.
+    fn forward(&self, send: &UnboundedSender<Outgoing>, outgoing: Outgoing) {
+        if let Err(e) = send.send(outgoing) {
+            debug!("dropping packet for {}: {}", self.sid, e);
+            self.unbuffer(1);
+        }
+    }
+
+    /// Send a message to the client. Strings are sent as text, and byte
+    /// vectors or `Bytes` as binary.
+    pub fn send<M: Into<Message>>(&self, msg: M) {
+        self.emit(Packet {
             id: ID::Message,
-            data: data
+            data: msg.into(),
         })
     }
 
-    /// Set callback for when a packet is sent to the client (message, ping)
-    pub fn on_packet<F>(&self, f: F)
-        where F: Fn(Packet) + 'static
+    /// Send a message to the client, returning a future that resolves once it
+    /// has been written to a polling response or a WebSocket frame, or fails
+    /// if the socket is closed first. The message is sent even if the future
+    /// is dropped.
+    pub fn send_flushed<M: Into<Message>>(&self, msg: M) -> Flushed {
+        let (send, mut recv) = oneshot::channel();
+        self.queue(Outgoing {
+            packet: Packet {
+                id: ID::Message,
+                data: msg.into(),
+            }.into(),
+            flushed: Some(send),
+        });
+
+        let socket = self.clone();
+        Flushed(Box::pin(async move {
+            tokio::select! {
+                biased;
+                flushed = &mut recv => flushed.map_err(|_| Closed),
+                // packets queued for a closed socket are never written
+                _ = socket.wait_closed() => Err(Closed),
+            }
+        }))
+    }
+
+    /// Join `room`, to receive the messages sent to it with `Server::to`
+    pub fn join(&self, room: &str) {
+        let mut rooms = self.rooms.write().unwrap();
+        // checked under the lock, as closing leaves all rooms
+        if !self.closed() {
+            rooms.entry(room.to_string()).or_default().insert(self.sid.clone());
+        }
+    }
+
+    /// Leave `room`
+    pub fn leave(&self, room: &str) {
+        let mut rooms = self.rooms.write().unwrap();
+        if let Some(members) = rooms.get_mut(room) {
+            members.remove(&self.sid);
+            if members.is_empty() {
+                rooms.remove(room);
+            }
+        }
+    }
+
+    fn leave_all(&self) {
+        self.rooms.write().unwrap().retain(|_, members| {
+            members.remove(&self.sid);
+            !members.is_empty()
+        });
+    }
+
+    /// Rooms the socket is in
+    pub fn rooms(&self) -> Vec<String> {
+        self.rooms
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|&(_, members)| members.contains(&self.sid))
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+
+    /// Recipients of a message to every client but this one. Adding rooms
+    /// with `Broadcast::to` sends it to the other clients in those rooms.
+    pub fn broadcast(&self) -> Broadcast {
+        Broadcast::new(self.client_map.clone(), self.rooms.clone()).except(&self.sid)
+    }
+
+    /// Start buffering the messages sent by the client, to be returned by the
+    /// first call to `messages`
+    #[doc(hidden)]
+    pub fn buffer_messages(&self) {
+        let messages = self.messages();
+        *self.early_messages.lock().unwrap() = Some(messages);
+    }
+
+    /// Stream of the messages sent by the client from now on, which ends when
+    /// the socket is closed. For sockets returned by `Server::accept`, the
+    /// first stream also has the messages sent since the handshake.
+    pub fn messages(&self) -> Messages {
+        if let Some(messages) = self.early_messages.lock().unwrap().take() {
+            return messages;
+        }
+        let (send, recv) = unbounded_channel();
+        let send = Arc::new(StdMutex::new(Some(send)));
+
+        let cl = send.clone();
+        let on_message = self.on_message(move |m| {
+            if let Some(ref send) = *cl.lock().unwrap() {
+                let _ = send.send(m.clone());
+            }
+        });
+        let cl = send.clone();
+        let on_close = self.on_close(move |_| {
+            cl.lock().unwrap().take();
+        });
+        if self.closed() {
+            send.lock().unwrap().take();
+        }
+
+        Messages {
+            recv,
+            handles: vec![on_message, on_close],
+        }
+    }
+
+    /// Sink sending messages to the client. It is only ready for more once
+    /// fewer than `capacity` packets are waiting to be sent, and flushed
+    /// once all of them have been written to the transport. Closing it does
+    /// not close the socket.
+    pub fn sink(&self, capacity: usize) -> MessageSink {
+        MessageSink {
+            socket: self.clone(),
+            capacity: capacity.max(1),
+            wait: None,
+        }
+    }
+
+    /// Add callback for when a packet is sent to the client (message, ping)
+    pub fn on_packet<F>(&self, f: F) -> Handle
+        where F: Fn(Packet) + Send + Sync + 'static
     {
-        let mut func = self.on_packet.write().unwrap();
-        *func = Some(Box::new(f));
+        self.on_packet.add(Arc::new(f))
     }
 
-    /// Set callback for when the write buffer is flushed
-    pub fn on_flush<F>(&self, f: F)
-        where F: Fn(&[Packet]) + 'static
+    /// Add callback for when the write buffer is flushed
+    pub fn on_flush<F>(&self, f: F) -> Handle
+        where F: Fn(&[Packet]) + Send + Sync + 'static
     {
-        let mut func = self.on_flush.write().unwrap();
-        *func = Some(Box::new(f))
+        self.on_flush.add(Arc::new(f))
     }
 
-    /// Set callback for when the client is disconnected
-    pub fn on_close<F>(&self, f: F)
-        where F: Fn(&str) + 'static
+    /// Add callback for when the client is disconnected
+    pub fn on_close<F>(&self, f: F) -> Handle
+        where F: Fn(&str) + Send + Sync + 'static
     {
-        let mut data = self.on_close.write().unwrap();
-        *data = Some(Box::new(f));
+        self.on_close.add(Arc::new(f))
     }
 
-    /// Set callback for when client sends a message
-    pub fn on_message<F>(&self, f: F)
-        where F: Fn(&[u8]) + 'static
+    /// Add callback for when client sends a message
+    pub fn on_message<F>(&self, f: F) -> Handle
+        where F: Fn(&Message) + Send + Sync + 'static
     {
-        let mut data = self.on_message.write().unwrap();
-        *data = Some(Box::new(f));
+        self.on_message.add(Arc::new(f))
     }
 
     #[inline]
     #[doc(hidden)]
-    pub fn call_on_message(&self, data: &[u8]) {
+    pub fn call_on_message(&self, data: &Message) {
         if self.closed() {
             return;
         }
-        if let Some(ref func) = *self.on_message.read().unwrap() {
+        for func in self.on_message.get() {
             func(data)
         }
     }
@@ -181,34 +588,413 @@ impl Socket {
         if self.closed() {
             return;
         }
-        if let Some(ref func) = *self.on_packet.read().unwrap() {
-            func(p)
+        for func in self.on_packet.get() {
+            func(p.clone())
         }
     }
 
+    /// Encode the packets waiting to be sent, for the response to a polling
+    /// GET request. If there are none, waits up to `wait` for one, after
+    /// which a noop packet is sent. Once the socket is closed, the client is
+    /// sent the remaining packets followed by a close packet.
     #[doc(hidden)]
-    pub fn encode_write_buffer(&self) -> Payload {
-        let Transport::Polling(_, ref lock) = self.transport;
-        let mut packets = vec![];
-        let recv = lock.lock().unwrap();
+    pub async fn encode_write_buffer(&self, wait: Duration) -> Payload {
+        let noop = || {
+            Outgoing::from(Packet {
+                id: ID::Noop,
+                data: Message::default(),
+            })
+        };
+        let lock = match *self.transport.read().unwrap() {
+            Transport::Polling(_, ref lock) => Some(lock.clone()),
+            Transport::WebSocket(_) => None,
+        };
+        let lock = match lock {
+            Some(lock) => lock,
+            // upgraded since the request was received, which ends the poll
+            // as when the upgrade happens during it
+            None => return self.encode_batch(vec![noop()]),
+        };
+        let mut batch = vec![];
+        let mut recv = lock.lock().await;
 
-        packets.push(recv.recv().unwrap());
-        while let Ok(packet) = recv.try_recv() {
-            packets.push(packet)
+        tokio::select! {
+            biased;
+            // the sender is dropped when the socket is upgraded to another
+            // transport, in which case the client is sent a noop to end the
+            // poll.
+            outgoing = recv.recv() => batch.push(outgoing.unwrap_or_else(noop)),
+            _ = self.wait_closed() => {}
+            _ = sleep(wait) => batch.push(noop()),
         }
+        while let Ok(outgoing) = recv.try_recv() {
+            batch.push(outgoing)
+        }
+        self.encode_batch(batch)
+    }
+
+    // Encodes packets taken from the polling queue, followed by a close
+    // packet if the socket is closed.
+    fn encode_batch(&self, mut batch: Vec<Outgoing>) -> Payload {
+        if self.closed() && batch.iter().all(|outgoing| outgoing.packet.id != ID::Close) {
+            batch.push(Outgoing::from(Packet {
+                id: ID::Close,
+                data: Message::default(),
+            }));
+        }
+
+        let (packets, flushed) = split_batch(batch);
+        let payload = match self.protocol {
+            Protocol::V3 => encode_payload(&packets, self.jsonp, self.b64, self.xhr2),
+            Protocol::V4 => encode_payload_v4(&packets, self.jsonp),
+        };
+        self.call_on_flush(&packets, flushed);
+        payload
+    }
 
-        self.call_on_flush(packets.as_slice());
-        encode_payload(&packets, self.jsonp, self.b64, self.xhr2)
+    #[doc(hidden)]
+    pub fn decode_payload(&self, data: Vec<u8>) -> Result<Vec<Packet>, Error> {
+        match self.protocol {
+            Protocol::V3 => decode_payload(data, self.b64, self.xhr2),
+            Protocol::V4 => decode_payload_v4(data),
+        }
     }
 
+    /// Called once `packets` have been written to the transport, with the
+    /// senders to notify from `split_batch`.
     #[inline]
-    fn call_on_flush(&self, packets: &[Packet]) {
-        if self.closed() {
+    #[doc(hidden)]
+    pub fn call_on_flush(&self, packets: &[SharedPacket], flushed: Vec<oneshot::Sender<()>>) {
+        self.unbuffer(packets.len());
+        for send in flushed {
+            let _ = send.send(());
+        }
+        let listeners = self.on_flush.get();
+        if self.closed() || listeners.is_empty() {
             return;
         }
 
-        if let Some(ref func) = *self.on_flush.read().unwrap() {
-            func(packets)
+        let packets: Vec<Packet> = packets.iter().map(|packet| (**packet).clone()).collect();
+        for func in listeners {
+            func(&packets)
+        }
+    }
+}
+
+/// Split packets taken from a transport queue from the senders to notify
+/// once they are written.
+#[doc(hidden)]
+pub fn split_batch(batch: Vec<Outgoing>) -> (Vec<SharedPacket>, Vec<oneshot::Sender<()>>) {
+    let mut flushed = vec![];
+    let packets = batch.into_iter()
+        .map(|outgoing| {
+            flushed.extend(outgoing.flushed);
+            outgoing.packet
+        })
+        .collect();
+    (packets, flushed)
+}
+
+/// Marks a polling request of a socket as in progress, returned by
+/// `Socket::start_poll` and `Socket::start_post`
+#[doc(hidden)]
+pub struct RequestGuard(Arc<AtomicBool>);
+
+impl RequestGuard {
+    fn new(active: &Arc<AtomicBool>) -> Option<RequestGuard> {
+        active.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| RequestGuard(active.clone()))
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Future returned by `Socket::send_flushed`
+pub struct Flushed(Pin<Box<dyn Future<Output = Result<(), Closed>> + Send>>);
+
+impl Future for Flushed {
+    type Output = Result<(), Closed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Closed>> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+/// Messages sent by the client, returned by `Socket::messages`
+pub struct Messages {
+    recv: UnboundedReceiver<Message>,
+    handles: Vec<Handle>,
+}
+
+impl Stream for Messages {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Message>> {
+        self.recv.poll_recv(cx)
+    }
+}
+
+impl Drop for Messages {
+    fn drop(&mut self) {
+        for handle in self.handles.drain(..) {
+            handle.remove();
+        }
+    }
+}
+
+/// Error returned when sending to a closed socket
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("socket closed")
+    }
+}
+
+impl StdError for Closed {}
+
+/// Sink sending messages to the client, returned by `Socket::sink`
+pub struct MessageSink {
+    socket: Socket,
+    capacity: usize,
+    wait: Option<(usize, WaitFuture)>,
+}
+
+type WaitFuture = Pin<Box<dyn Future<Output = bool> + Send>>;
+
+impl MessageSink {
+    // Waits until fewer than `n` packets are waiting to be sent.
+    fn poll_buffered_below(&mut self, cx: &mut Context, n: usize) -> Poll<Result<(), Closed>> {
+        loop {
+            if self.socket.closed() {
+                self.wait = None;
+                return Poll::Ready(Err(Closed));
+            }
+            if self.socket.buffered() < n {
+                self.wait = None;
+                return Poll::Ready(Ok(()));
+            }
+
+            if self.wait.as_ref().is_none_or(|&(below, _)| below != n) {
+                let socket = self.socket.clone();
+                self.wait = Some((n, Box::pin(async move { socket.wait_buffered_below(n).await })));
+            }
+            if let Some((_, ref mut wait)) = self.wait {
+                ready!(wait.as_mut().poll(cx));
+            }
+            self.wait = None;
+        }
+    }
+}
+
+impl<M: Into<Message>> Sink<M> for MessageSink {
+    type Error = Closed;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Closed>> {
+        let capacity = self.capacity;
+        self.poll_buffered_below(cx, capacity)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: M) -> Result<(), Closed> {
+        if self.socket.closed() {
+            return Err(Closed);
         }
+        self.socket.send(msg);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Closed>> {
+        self.poll_buffered_below(cx, 1)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Closed>> {
+        self.poll_buffered_below(cx, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{poll_fn, Future};
+    use std::pin::Pin;
+    use std::task::Poll;
+    use std::sync::{Arc, RwLock};
+    use std::sync::Mutex as StdMutex;
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+    use hyper::HeaderMap;
+    use tokio::sync::Mutex;
+    use tokio::sync::mpsc::unbounded_channel;
+    use crate::config::SystemClock;
+    use crate::packet::{Message, Packet, Protocol, ID};
+    use futures_util::{Sink, SinkExt, StreamExt};
+    use super::{Closed, Handshake, Socket, Transport};
+
+    const WAIT: Duration = Duration::from_secs(30);
+
+    fn polling_socket(protocol: Protocol) -> Socket {
+        let (send, recv) = unbounded_channel();
+        Socket::new(Arc::new("sid".to_string()),
+                    Transport::Polling(send, Arc::new(Mutex::new(recv))),
+                    Arc::new(RwLock::new(HashMap::new())),
+                    Arc::new(RwLock::new(HashMap::new())),
+                    Handshake {
+                        headers: HeaderMap::new(),
+                        query: HashMap::new(),
+                        address: None,
+                        time: SystemTime::now(),
+                        protocol,
+                        transport: "polling",
+                        secure: false,
+                    },
+                    false,
+                    None,
+                    Arc::new(SystemClock))
+    }
+
+    #[tokio::test]
+    async fn upgrade_keeps_buffered_packets() {
+        let so = polling_socket(Protocol::V3);
+        so.send(b"first".to_vec());
+        so.emit(Packet {
+            id: ID::Noop,
+            data: Message::default(),
+        });
+        so.send("second");
+
+        let (send, mut recv) = unbounded_channel();
+        so.upgrade(Transport::WebSocket(send)).await;
+        assert!(!so.is_polling());
+        so.send("third");
+
+        let mut data = vec![];
+        while let Ok(outgoing) = recv.try_recv() {
+            data.push(outgoing.packet.data.clone());
+        }
+        assert_eq!(data,
+                   vec![Message::from(b"first".to_vec()),
+                        Message::from("second"),
+                        Message::from("third")]);
+    }
+
+    #[tokio::test]
+    async fn upgrade_orders_concurrent_packets() {
+        let so = polling_socket(Protocol::V4);
+        so.send("first");
+
+        // holds the polling queue as a GET in progress would
+        let lock = match *so.transport.read().unwrap() {
+            Transport::Polling(_, ref lock) => lock.clone(),
+            Transport::WebSocket(_) => unreachable!(),
+        };
+        let guard = lock.lock().await;
+
+        let (send, mut recv) = unbounded_channel();
+        let cl = so.clone();
+        let upgrade = tokio::spawn(async move { cl.upgrade(Transport::WebSocket(send)).await });
+        tokio::task::yield_now().await;
+        assert!(!so.is_polling());
+        so.send("second");
+
+        drop(guard);
+        upgrade.await.unwrap();
+        so.send("third");
+
+        let mut data = vec![];
+        while let Ok(outgoing) = recv.try_recv() {
+            data.push(outgoing.packet.data.clone());
+        }
+        assert_eq!(data,
+                   vec![Message::from("first"), Message::from("second"), Message::from("third")]);
+        assert_eq!(so.buffered(), 3);
+    }
+
+    #[test]
+    fn extensions() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct User(u32);
+
+        let mut so = polling_socket(Protocol::V4);
+        let cl = so.clone();
+        assert_eq!(so.extensions().insert(User(1)), None);
+        assert_eq!(cl.extensions().insert(User(2)), Some(User(1)));
+        assert_eq!(so.extensions().with(|u: &mut User| u.0 += 1), Some(()));
+        assert_eq!(so.extensions().get::<User>(), Some(User(3)));
+        assert_eq!(so.extensions().get::<u32>(), None);
+
+        let ext = so.extensions().clone();
+        so.on_close(move |_| assert!(ext.contains::<User>()));
+        so.close("test");
+        assert!(!cl.extensions().contains::<User>());
+    }
+
+    #[test]
+    fn listeners() {
+        let so = polling_socket(Protocol::V4);
+        let received = Arc::new(StdMutex::new(vec![]));
+        let cl = received.clone();
+        let first = so.on_message(move |m| cl.lock().unwrap().push((1, m.clone())));
+        let cl = received.clone();
+        so.on_message(move |m| cl.lock().unwrap().push((2, m.clone())));
+
+        so.call_on_message(&Message::from("a"));
+        first.remove();
+        so.call_on_message(&Message::from("b"));
+        assert_eq!(*received.lock().unwrap(),
+                   vec![(1, Message::from("a")),
+                        (2, Message::from("a")),
+                        (2, Message::from("b"))]);
+    }
+
+    #[tokio::test]
+    async fn messages_and_sink() {
+        let mut so = polling_socket(Protocol::V4);
+        let mut messages = so.messages();
+        so.call_on_message(&Message::from("a"));
+        assert_eq!(messages.next().await, Some(Message::from("a")));
+
+        let mut sink = so.sink(2);
+        sink.feed("b").await.unwrap();
+        sink.feed("c").await.unwrap();
+        let ready = poll_fn(|cx| Poll::Ready(Sink::<&str>::poll_ready(Pin::new(&mut sink), cx)));
+        assert!(ready.await.is_pending());
+        assert_eq!(so.encode_write_buffer(WAIT).await.0, b"4b\x1e4c");
+        assert_eq!(so.buffered(), 0);
+        // sending waits for the message to be flushed
+        let (sent, payload) = tokio::join!(sink.send("d"), so.encode_write_buffer(WAIT));
+        assert_eq!(sent, Ok(()));
+        assert_eq!(payload.0, b"4d");
+
+        so.close("test");
+        assert_eq!(messages.next().await, None);
+        assert_eq!(sink.feed("e").await, Err(Closed));
+    }
+
+    #[tokio::test]
+    async fn send_flushed() {
+        let mut so = polling_socket(Protocol::V4);
+        let mut flushed = so.send_flushed("a");
+        assert!(poll_fn(|cx| Poll::Ready(Pin::new(&mut flushed).poll(cx).is_pending())).await);
+        assert_eq!(so.encode_write_buffer(WAIT).await.0, b"4a");
+        assert_eq!(flushed.await, Ok(()));
+
+        let flushed = so.send_flushed("b");
+        so.close("test");
+        assert_eq!(flushed.await, Err(Closed));
+        assert_eq!(so.send_flushed("c").await, Err(Closed));
+    }
+
+    #[tokio::test]
+    async fn poll_after_upgrade() {
+        let so = polling_socket(Protocol::V4);
+        let (send, _recv) = unbounded_channel();
+        so.upgrade(Transport::WebSocket(send)).await;
+        // a GET racing the upgrade ends with a noop
+        assert_eq!(so.encode_write_buffer(WAIT).await.0, b"6");
     }
 }